@@ -0,0 +1,109 @@
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs;
+
+/// An image that is attached to a message. The image itself is copied into the conversation's
+/// attachment directory, the message only stores its file name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attachment {
+    file_name: String,
+    mime_type: String,
+    width: usize,
+    height: usize,
+    /// Base64 encoded image, only loaded right before the message is sent to the API.
+    #[serde(skip)]
+    data: Option<String>,
+}
+
+impl Attachment {
+    /// Copies the image at `source` into `attachment_dir` and returns an attachment that refers to
+    /// the copy.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file is not a supported image or cannot be
+    /// copied.
+    pub async fn import(source: &Path, attachment_dir: &Path) -> Result<Self> {
+        let extension = source
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase())
+            .unwrap_or_default();
+
+        let mime_type = match extension.as_str() {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            _ => return Err(anyhow!("Unsupported image type: {:?}", source)),
+        };
+
+        let size = imagesize::size(source)
+            .with_context(|| format!("Failed to read image size of {:?}", source))?;
+
+        if !attachment_dir.exists() {
+            fs::create_dir_all(attachment_dir).await?;
+        }
+
+        let file_name = format!("{:016x}.{}", thread_rng().gen::<u64>(), extension);
+        fs::copy(source, attachment_dir.join(&file_name))
+            .await
+            .with_context(|| format!("Failed to copy {:?} into the save directory", source))?;
+
+        Ok(Self {
+            file_name,
+            mime_type: mime_type.to_string(),
+            width: size.width,
+            height: size.height,
+            data: None,
+        })
+    }
+
+    /// Reads the image from `attachment_dir` so it can be sent to the API.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the image has been removed from the save directory.
+    pub async fn load_data(&mut self, attachment_dir: &Path) -> Result<()> {
+        let bytes = fs::read(attachment_dir.join(&self.file_name))
+            .await
+            .with_context(|| format!("Failed to read attachment {}", self.file_name))?;
+        self.data = Some(base64::engine::general_purpose::STANDARD.encode(bytes));
+        Ok(())
+    }
+
+    /// Returns the image as a `data:` url, or `None` if `load_data` hasn't been called.
+    pub fn get_data_url(&self) -> Option<String> {
+        self.data
+            .as_ref()
+            .map(|data| format!("data:{};base64,{}", self.mime_type, data))
+    }
+
+    /// Returns the number of tokens this image costs at high detail.
+    ///
+    /// The image is scaled to fit in a 2048x2048 square, then scaled so its shortest side is at
+    /// most 768px. Every 512px tile costs 170 tokens on top of a base of 85.
+    /// https://platform.openai.com/docs/guides/vision/calculating-costs
+    pub fn get_token_count(&self) -> usize {
+        let mut width = self.width as f64;
+        let mut height = self.height as f64;
+
+        if width > 2048.0 || height > 2048.0 {
+            let scale = 2048.0 / width.max(height);
+            width *= scale;
+            height *= scale;
+        }
+
+        if width.min(height) > 768.0 {
+            let scale = 768.0 / width.min(height);
+            width *= scale;
+            height *= scale;
+        }
+
+        let tiles = (width / 512.0).ceil() as usize * (height / 512.0).ceil() as usize;
+        85 + 170 * tiles
+    }
+}
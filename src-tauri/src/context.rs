@@ -0,0 +1,365 @@
+use crate::gpt::{Message, Role, SYSTEM_PROMPT};
+use crate::models::Model;
+use crate::tokenizer;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Room that is kept free for the response when `max_tokens` is not set.
+pub const DEFAULT_RESPONSE_TOKENS: usize = 1024;
+
+/// Room that is kept free for the summary of the messages that are left out, the summary is
+/// limited to this many tokens.
+pub const SUMMARY_TOKENS: usize = 512;
+
+#[derive(Error, Debug)]
+pub enum ContextError {
+    #[error(
+        "The conversation needs {token_count} tokens, but the model only fits {context_window}"
+    )]
+    TooLong {
+        token_count: usize,
+        context_window: usize,
+    },
+}
+
+/// What happens when a conversation doesn't fit in the context window of the model.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategy {
+    /// The oldest messages are left out
+    #[default]
+    DropOldest,
+    /// The oldest messages are replaced by a summary the model writes of them
+    Summarize,
+    /// The conversation isn't sent
+    Refuse,
+}
+
+/// Payload of the `context_trimmed` event, which is emitted when messages were left out of a
+/// request to make it fit.
+#[derive(Clone, Serialize)]
+pub struct Trimmed {
+    strategy: ContextStrategy,
+    /// Number of messages that were left out or summarized
+    message_count: usize,
+    tokens_before: usize,
+    tokens_after: usize,
+    /// Why the messages couldn't be summarized, they are left out instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary_error: Option<String>,
+}
+
+/// The messages that are sent to the model.
+pub struct Context {
+    pub messages: Vec<Message>,
+    /// The messages that were left out to make the rest fit, oldest first
+    pub left_out: Vec<Message>,
+    /// What was left out, `None` if everything fit
+    pub trimmed: Option<Trimmed>,
+}
+
+impl Context {
+    /// Puts `summary` of the messages that were left out in front of the other messages.
+    pub fn add_summary(&mut self, summary: String, model: &Model) {
+        let summary = Message::new(
+            Role::system,
+            format!("Summary of the earlier conversation: {summary}"),
+        );
+        if let Some(trimmed) = &mut self.trimmed {
+            trimmed.tokens_after += tokenizer::count_message_tokens(&summary, model.get_encoding());
+        }
+        self.messages.insert(0, summary);
+    }
+
+    /// Records that the messages that were left out couldn't be summarized, so they are simply
+    /// dropped.
+    pub fn set_summary_failed(&mut self, error: String) {
+        if let Some(trimmed) = &mut self.trimmed {
+            trimmed.strategy = ContextStrategy::DropOldest;
+            trimmed.summary_error = Some(error);
+        }
+    }
+}
+
+/// Decides which messages of a conversation are sent, so the request fits in the context window
+/// of the model with room to spare for the response.
+pub struct ContextBuilder<'a> {
+    model: &'a Model,
+    strategy: ContextStrategy,
+    /// Tokens that are kept free for the response
+    response_tokens: usize,
+    /// Whether the last message is the prompt that asks the model to continue its answer
+    continuation: bool,
+}
+
+impl<'a> ContextBuilder<'a> {
+    pub fn new(model: &'a Model, strategy: ContextStrategy, response_tokens: usize) -> Self {
+        Self {
+            model,
+            strategy,
+            response_tokens,
+            continuation: false,
+        }
+    }
+
+    /// Marks the last message as the prompt that asks the model to continue its answer. The
+    /// answer and the prompt it answers are then protected, instead of only the last message.
+    pub fn with_continuation(mut self, continuation: bool) -> Self {
+        self.continuation = continuation;
+        self
+    }
+
+    /// Returns the messages that are sent to the model. When they don't fit, the oldest messages
+    /// are left out, except for pinned messages and the last prompt with everything after it.
+    /// Messages are left out an exchange at a time, a prompt with its answer and tool calls, so
+    /// tool results never lose their call and the conversation still starts with a prompt.
+    /// Messages that are never sent are left out right away.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the messages don't fit and the strategy is
+    /// `ContextStrategy::Refuse`, or if they don't fit even without the messages that may be
+    /// left out.
+    pub fn build(&self, messages: Vec<Message>) -> Result<Context, ContextError> {
+        let messages: Vec<Message> = messages
+            .into_iter()
+            .filter(|message| message.is_sent())
+            .collect();
+        let encoding = self.model.get_encoding();
+        let system_prompt = Message::new(Role::system, SYSTEM_PROMPT.to_string());
+        let tokens_before = tokenizer::count_prompt_tokens(
+            std::iter::once(&system_prompt).chain(&messages),
+            encoding,
+        );
+
+        let context_window = match self.model.get_context_window() {
+            Some(context_window) => context_window,
+            None => return Ok(Self::untrimmed(messages)),
+        };
+        let too_long = |token_count: usize| ContextError::TooLong {
+            token_count: token_count + self.response_tokens,
+            context_window,
+        };
+
+        let mut budget = context_window.saturating_sub(self.response_tokens);
+        if tokens_before <= budget {
+            return Ok(Self::untrimmed(messages));
+        }
+        match self.strategy {
+            ContextStrategy::Refuse => return Err(too_long(tokens_before)),
+            ContextStrategy::Summarize => budget = budget.saturating_sub(SUMMARY_TOKENS),
+            ContextStrategy::DropOldest => {}
+        }
+
+        // The last prompt is what the model is answering, a continuation prompt only asks it to
+        // go on with the answer to the prompt before it
+        let searched = match self.continuation {
+            true => messages.len().saturating_sub(1),
+            false => messages.len(),
+        };
+        let protected_from = messages[..searched]
+            .iter()
+            .rposition(|message| *message.get_role() == Role::user)
+            .unwrap_or(messages.len().saturating_sub(1));
+
+        let mut left_out = vec![false; messages.len()];
+        let mut token_count = tokens_before;
+        let mut index = 0;
+        while index < protected_from && token_count > budget {
+            let exchange_end = index
+                + 1
+                + messages[index + 1..protected_from]
+                    .iter()
+                    .take_while(|message| *message.get_role() != Role::user)
+                    .count();
+            let exchange = &messages[index..exchange_end];
+
+            if !exchange.iter().any(|message| message.is_pinned()) {
+                for (offset, message) in exchange.iter().enumerate() {
+                    left_out[index + offset] = true;
+                    token_count -= tokenizer::count_message_tokens(message, encoding);
+                }
+            }
+            index = exchange_end;
+        }
+
+        if token_count > budget {
+            return Err(too_long(token_count));
+        }
+
+        let mut context = Context {
+            messages: vec![],
+            left_out: vec![],
+            trimmed: None,
+        };
+        for (left_out, message) in left_out.into_iter().zip(messages) {
+            match left_out {
+                true => context.left_out.push(message),
+                false => context.messages.push(message),
+            }
+        }
+        context.trimmed = Some(Trimmed {
+            strategy: self.strategy,
+            message_count: context.left_out.len(),
+            tokens_before,
+            tokens_after: token_count,
+            summary_error: None,
+        });
+        Ok(context)
+    }
+
+    fn untrimmed(messages: Vec<Message>) -> Context {
+        Context {
+            messages,
+            left_out: vec![],
+            trimmed: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Leaves room for the system prompt and a few short messages, but not for a long one.
+    const CONTEXT_WINDOW: usize = 600;
+    const RESPONSE_TOKENS: usize = 10;
+
+    fn model(context_window: usize) -> Model {
+        serde_json::from_value(serde_json::json!({
+            "id": "test",
+            "provider": "openai",
+            "context_window": context_window,
+        }))
+        .unwrap()
+    }
+
+    fn short(role: Role) -> Message {
+        Message::new(role, "Short".to_string())
+    }
+
+    fn long(role: Role) -> Message {
+        Message::new(role, "word ".repeat(1000))
+    }
+
+    fn contents(messages: &[Message]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.get_content())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_everything_that_fits() {
+        let model = model(CONTEXT_WINDOW);
+        let context = ContextBuilder::new(&model, ContextStrategy::DropOldest, RESPONSE_TOKENS)
+            .build(vec![short(Role::user), short(Role::assistant)])
+            .unwrap();
+
+        assert_eq!(context.messages.len(), 2);
+        assert!(context.left_out.is_empty());
+        assert!(context.trimmed.is_none());
+    }
+
+    #[test]
+    fn drops_the_oldest_exchanges() {
+        let model = model(CONTEXT_WINDOW);
+        let context = ContextBuilder::new(&model, ContextStrategy::DropOldest, RESPONSE_TOKENS)
+            .build(vec![
+                long(Role::user),
+                long(Role::assistant),
+                Message::new(Role::user, "Last".to_string()),
+            ])
+            .unwrap();
+
+        assert_eq!(contents(&context.messages), ["Last"]);
+        assert_eq!(context.left_out.len(), 2);
+        let trimmed = context.trimmed.unwrap();
+        assert_eq!(trimmed.message_count, 2);
+        assert!(trimmed.tokens_after <= CONTEXT_WINDOW - RESPONSE_TOKENS);
+    }
+
+    #[test]
+    fn never_drops_pinned_messages() {
+        let model = model(CONTEXT_WINDOW);
+        let mut pinned = long(Role::user);
+        pinned.set_pinned(true);
+
+        let result = ContextBuilder::new(&model, ContextStrategy::DropOldest, RESPONSE_TOKENS)
+            .build(vec![pinned, long(Role::assistant), short(Role::user)]);
+        assert!(matches!(result, Err(ContextError::TooLong { .. })));
+    }
+
+    #[test]
+    fn refuses_when_it_does_not_fit() {
+        let model = model(CONTEXT_WINDOW);
+        let result =
+            ContextBuilder::new(&model, ContextStrategy::Refuse, RESPONSE_TOKENS).build(vec![
+                long(Role::user),
+                long(Role::assistant),
+                short(Role::user),
+            ]);
+        assert!(matches!(result, Err(ContextError::TooLong { .. })));
+    }
+
+    #[test]
+    fn leaves_room_for_the_summary() {
+        let messages = vec![long(Role::user), long(Role::assistant), short(Role::user)];
+
+        // Without the room for the summary it would fit
+        let small = model(CONTEXT_WINDOW);
+        let result = ContextBuilder::new(&small, ContextStrategy::Summarize, RESPONSE_TOKENS)
+            .build(messages.clone());
+        assert!(matches!(result, Err(ContextError::TooLong { .. })));
+
+        let large = model(CONTEXT_WINDOW + SUMMARY_TOKENS);
+        let context = ContextBuilder::new(&large, ContextStrategy::Summarize, RESPONSE_TOKENS)
+            .build(messages)
+            .unwrap();
+        assert_eq!(context.left_out.len(), 2);
+        assert!(matches!(
+            context.trimmed.unwrap().strategy,
+            ContextStrategy::Summarize
+        ));
+    }
+
+    #[test]
+    fn drops_the_messages_that_cannot_be_summarized() {
+        let model = model(CONTEXT_WINDOW + SUMMARY_TOKENS);
+        let mut context = ContextBuilder::new(&model, ContextStrategy::Summarize, RESPONSE_TOKENS)
+            .build(vec![
+                long(Role::user),
+                long(Role::assistant),
+                short(Role::user),
+            ])
+            .unwrap();
+
+        context.set_summary_failed("Could not reach the API".to_string());
+
+        assert_eq!(contents(&context.messages), vec!["Short"]);
+        let trimmed = context.trimmed.unwrap();
+        assert!(matches!(trimmed.strategy, ContextStrategy::DropOldest));
+        assert_eq!(
+            trimmed.summary_error.as_deref(),
+            Some("Could not reach the API")
+        );
+    }
+
+    #[test]
+    fn protects_the_answer_that_is_continued() {
+        let model = model(CONTEXT_WINDOW);
+        let messages = vec![
+            short(Role::user),
+            short(Role::assistant),
+            short(Role::user),
+            long(Role::assistant),
+            Message::new(Role::user, "Continue".to_string()),
+        ];
+
+        // Dropping the answer that is being continued would only leave the continuation prompt
+        let result = ContextBuilder::new(&model, ContextStrategy::DropOldest, RESPONSE_TOKENS)
+            .with_continuation(true)
+            .build(messages);
+        assert!(matches!(result, Err(ContextError::TooLong { .. })));
+    }
+}
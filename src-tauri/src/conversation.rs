@@ -1,13 +1,26 @@
 use super::gpt;
-use crate::gpt::{MessageDelta, Request, Role};
-use crate::settings::Model;
-use anyhow::{Context, Result};
+use crate::attachment::Attachment;
+use crate::context::{
+    ContextBuilder, ContextError, ContextStrategy, DEFAULT_RESPONSE_TOKENS, SUMMARY_TOKENS,
+};
+use crate::gpt::{
+    ApiError, FinishReason, MessageDelta, Request, ResponseFormat, Role, SamplingParameters,
+    ToolCall, Usage, SYSTEM_PROMPT,
+};
+use crate::message_tree::{Branches, MessageTree};
+use crate::models::Model;
+use crate::providers::{ChatProvider, DeltaStream, ProviderError};
+use crate::settings::Settings;
+use crate::tokenizer;
+use crate::tools::ToolRegistry;
+use anyhow::{anyhow, Context, Result};
 use directories::BaseDirs;
 use gpt::Message;
 use rand::prelude::*;
-use reqwest_eventsource::CannotCloneRequestError;
-use serde::{Deserialize, Serialize};
-use std::time::{self, Duration};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{self, Duration, Instant};
 use std::{
     path::PathBuf,
     sync::{
@@ -27,17 +40,214 @@ pub enum PromptError {
     ConversationLocked,
 
     #[error("Something went wrong while making the API request")]
-    RequestError(#[from] CannotCloneRequestError),
+    RequestError(#[from] ProviderError),
+
+    #[error("There is no message at index {0}")]
+    InvalidMessageIndex(usize),
+
+    #[error("The message has no candidate {0}")]
+    InvalidCandidate(usize),
+
+    #[error("There is no answer to continue")]
+    NothingToContinue,
+
+    #[error("There is no answer to regenerate")]
+    NothingToRegenerate,
+
+    #[error("The message at index {0} is not a prompt")]
+    NotAPrompt(usize),
+
+    #[error("The message has no alternative {0}")]
+    InvalidBranch(usize),
+
+    #[error("{0} can't see images")]
+    VisionNotSupported(String),
+
+    #[error(transparent)]
+    ContextTooLong(#[from] ContextError),
 }
 
 #[derive(Clone)]
 pub struct Conversation {
     is_locked: Arc<AtomicBool>, // Conversation will be locked while the server is streaming a response
     // to us.
-    messages: Arc<Mutex<Vec<Message>>>,
+    /// Every message that was sent or received, including the alternatives that were replaced
+    /// by editing a prompt or regenerating an answer.
+    messages: Arc<Mutex<MessageTree>>,
     name: Arc<Mutex<Option<String>>>,
     id: Arc<AtomicU32>,
     date_created: Arc<AtomicU64>,
+    /// Overrides for the sampling parameters in the settings.
+    parameters: Arc<Mutex<SamplingParameters>>,
+    /// When set, the model has to answer with JSON and every answer is validated.
+    response_format: Arc<Mutex<Option<ResponseFormat>>>,
+    /// Stops the response that is being streamed, without affecting other conversations.
+    cancel_state: CancelState,
+    /// The last summary of messages that were left out of a request, with a hash of those
+    /// messages, so it can be reused until other messages are left out.
+    summary: Arc<Mutex<Option<(u64, String)>>>,
+}
+
+/// The model may keep calling tools forever, so we stop sending tool results after this many
+/// rounds.
+const MAX_TOOL_ROUNDS: usize = 8;
+
+/// The result of the tool calls that are made after `MAX_TOOL_ROUNDS`. Every call needs a result,
+/// or the conversation can't be sent again.
+const TOOL_LIMIT_RESULT: &str = "The tool was not called, the limit of tool calls was reached.";
+
+/// How often the `thinking` event is emitted while a reasoning model hasn't sent any content.
+const THINKING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reasoning models send nothing while they think, so instead of the read timeout the response
+/// is given this long to start.
+const MAX_THINKING_TIME: Duration = Duration::from_secs(600);
+
+/// Sent after the messages that are left out of a request, to have the model summarize them.
+const SUMMARY_PROMPT: &str = "Summarize the conversation above in a few paragraphs. Keep every fact, decision and piece of code that may be needed later. Do not say anything except the summary.";
+
+/// Sent after the conversation to have the model name it.
+const NAMING_PROMPT: &str = "Write a name for this conversation, it should not be longer than a few words. Do not mention math if the user doesn't. Do not say anything except the name, do not put it in quotes and do not use a period.";
+
+/// The name of a conversation that couldn't be named by the model.
+const DEFAULT_NAME: &str = "New conversation";
+
+/// Sent as a prompt to have the model continue an answer that was cut off.
+const CONTINUE_PROMPT: &str = "Your previous answer was cut off. Continue it exactly where it stopped, without repeating anything or adding an introduction.";
+
+/// Payload of the `validation` event, which is emitted after a structured response has been
+/// received. When it is invalid, the errors are also added to the conversation as an error
+/// message.
+#[derive(Clone, Serialize)]
+struct ValidationResult {
+    valid: bool,
+    errors: Vec<String>,
+}
+
+/// Token and cost statistics of a conversation, shown in the UI.
+#[derive(Clone, Serialize)]
+pub struct ConversationStats {
+    message_count: usize,
+    token_count: usize,
+    context_window: Option<usize>,
+    /// What sending the conversation as it is would cost, without the response
+    prompt_cost_dollars: f32,
+    /// What has been spent on this conversation so far
+    total_cost_dollars: f32,
+}
+
+/// A generated token and how likely the model thought it was.
+#[derive(Clone, Serialize)]
+pub struct TokenProbability {
+    token: String,
+    probability: f32,
+    /// The tokens the model considered instead, most likely first
+    alternatives: Vec<Alternative>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Alternative {
+    token: String,
+    probability: f32,
+}
+
+/// Payload of the `cost` event, the total cost of the last response. That includes the answers
+/// to tool results and the answer that was replaced by an error.
+#[derive(Clone, Debug, Serialize)]
+struct Cost {
+    dollars: f32,
+    /// Whether the provider didn't report its usage, so the cost is based on our own estimate
+    estimated: bool,
+    /// Tokens a reasoning model spent thinking, they are part of the cost
+    reasoning_tokens: usize,
+}
+
+/// Payload of the `thinking` event, which is emitted every second while a reasoning model is
+/// thinking.
+#[derive(Clone, Serialize)]
+struct Thinking {
+    elapsed_ms: u64,
+}
+
+/// Payload of the `api_error` event, which is emitted when a response fails.
+#[derive(Clone, Serialize)]
+struct ApiErrorEvent {
+    error: ApiError,
+    message: String,
+}
+
+/// Payload of the `retrying` event, which is emitted when a request failed and is sent again.
+#[derive(Clone, Serialize)]
+struct Retrying {
+    attempt: u32,
+    delay_ms: u64,
+}
+
+/// Payload of the `add_candidate_content` event.
+#[derive(Clone, Serialize)]
+struct CandidateContent {
+    index: usize,
+    content: String,
+}
+
+/// Wraps the payload of every event that is emitted for a conversation, so the frontend knows
+/// which conversation it belongs to.
+#[derive(Clone, Serialize)]
+struct ConversationEvent<T> {
+    conversation_id: u32,
+    payload: T,
+}
+
+/// Emits events on the window on behalf of a single conversation.
+#[derive(Clone)]
+pub struct EventEmitter {
+    /// Events are dropped when there is no window, like in tests
+    window: Option<tauri::Window>,
+    conversation_id: u32,
+}
+
+impl EventEmitter {
+    pub fn new(window: &tauri::Window, conversation_id: u32) -> Self {
+        Self {
+            window: Some(window.clone()),
+            conversation_id,
+        }
+    }
+
+    /// Returns an emitter that drops every event.
+    #[cfg(test)]
+    fn detached(conversation_id: u32) -> Self {
+        Self {
+            window: None,
+            conversation_id,
+        }
+    }
+
+    pub fn emit<T: Serialize + Clone>(&self, event: &str, payload: T) {
+        if let Some(window) = &self.window {
+            window
+                .emit(
+                    event,
+                    ConversationEvent {
+                        conversation_id: self.conversation_id,
+                        payload,
+                    },
+                )
+                .unwrap();
+        }
+    }
+}
+
+/// Everything that was received while reading a response stream.
+#[derive(Default)]
+struct StreamResponse {
+    output: String,
+    usage: Usage,
+    tool_calls: Vec<ToolCall>,
+    finish_reason: Option<FinishReason>,
+    /// The error that ended the stream, if it didn't end normally
+    error: Option<ApiError>,
+    cancelled: bool,
 }
 
 #[derive(Clone)]
@@ -59,11 +269,32 @@ impl CancelState {
     }
 }
 
+/// Keeps a conversation locked while a response is requested. It is taken before anything else
+/// happens, so two commands can't both start a response, and the conversation is unlocked again
+/// when it is dropped, so a request that fails before it is sent doesn't leave it locked.
+pub(crate) struct ResponseLock(Option<Arc<AtomicBool>>);
+
+impl ResponseLock {
+    /// Leaves the conversation locked after the response has started, the task that streams it
+    /// unlocks the conversation when it is done.
+    fn hand_over(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ResponseLock {
+    fn drop(&mut self) {
+        if let Some(is_locked) = &self.0 {
+            is_locked.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
 impl Conversation {
     pub fn new() -> Self {
         Self {
             is_locked: Arc::new(AtomicBool::new(false)),
-            messages: Arc::new(Mutex::new(vec![])),
+            messages: Arc::new(Mutex::new(MessageTree::default())),
             name: Arc::new(Mutex::new(None)),
             id: Arc::new(AtomicU32::new(thread_rng().gen())),
             date_created: Arc::new(AtomicU64::new(
@@ -72,40 +303,297 @@ impl Conversation {
                     .unwrap()
                     .as_secs(),
             )),
+            parameters: Arc::new(Mutex::new(SamplingParameters::default())),
+            response_format: Arc::new(Mutex::new(None)),
+            cancel_state: CancelState::new(),
+            summary: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub async fn reset(&self) {
-        self.is_locked.store(false, Ordering::SeqCst);
-        *self.messages.lock().await = vec![];
-        *self.name.lock().await = None;
-        self.id.store(thread_rng().gen(), Ordering::Relaxed);
-        self.date_created.store(
-            time::SystemTime::now()
-                .duration_since(time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            Ordering::Relaxed,
-        );
+    /// Locks this conversation for a new response.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is already locked.
+    pub(crate) fn lock_for_response(&self) -> Result<ResponseLock, PromptError> {
+        self.is_locked
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .map_err(|_| PromptError::ConversationLocked)?;
+        Ok(ResponseLock(Some(Arc::clone(&self.is_locked))))
+    }
+
+    /// Stops the response that is being streamed, if there is one.
+    pub fn cancel(&self) {
+        if self.is_locked.load(Ordering::SeqCst) {
+            self.cancel_state.transmit_cancel();
+        }
     }
 
-    pub fn get_messages(&self) -> &Arc<Mutex<Vec<Message>>> {
+    pub fn get_messages(&self) -> &Arc<Mutex<MessageTree>> {
         &self.messages
     }
 
+    pub async fn get_parameters(&self) -> SamplingParameters {
+        self.parameters.lock().await.clone()
+    }
+
+    pub async fn set_parameters(&self, parameters: SamplingParameters) {
+        *self.parameters.lock().await = parameters;
+    }
+
+    pub async fn get_response_format(&self) -> Option<ResponseFormat> {
+        self.response_format.lock().await.clone()
+    }
+
+    pub async fn set_response_format(&self, response_format: Option<ResponseFormat>) {
+        *self.response_format.lock().await = response_format;
+    }
+
+    /// Returns the sampling parameters that will be sent with the next request, which are the
+    /// parameters from the settings combined with this conversation's overrides.
+    pub async fn get_effective_parameters(&self, settings: &Settings) -> SamplingParameters {
+        settings
+            .get_sampling()
+            .merge(&*self.parameters.lock().await)
+    }
+
     pub fn get_id(&self) -> u32 {
         return self.id.load(Ordering::Relaxed);
     }
 
-    pub async fn get_word_count(&self) -> usize {
+    pub fn get_date_created(&self) -> u64 {
+        self.date_created.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of tokens the conversation takes up when it is sent to `model`,
+    /// including the system prompt and the formatting of the chat format.
+    pub async fn get_token_count(&self, model: &Model) -> usize {
+        let messages = self.messages.lock().await;
+        let system_prompt = Message::new(Role::system, SYSTEM_PROMPT.to_string());
+
+        let sent_messages = messages.iter().filter(|message| message.is_sent());
+
+        tokenizer::count_prompt_tokens(
+            std::iter::once(&system_prompt).chain(sent_messages),
+            model.get_encoding(),
+        )
+    }
+
+    pub async fn get_stats(&self, model: &Model) -> ConversationStats {
+        let token_count = self.get_token_count(model).await;
+        let messages = self.messages.lock().await;
+
+        ConversationStats {
+            message_count: messages
+                .iter()
+                .filter(|message| !message.is_deleted())
+                .count(),
+            token_count,
+            context_window: model.get_context_window(),
+            prompt_cost_dollars: model.calculate_cost(token_count, 0, 0),
+            // The alternatives were paid for as well
+            total_cost_dollars: messages
+                .iter_all()
+                .filter_map(|message| message.get_cost())
+                .sum(),
+        }
+    }
+
+    /// Returns the number of tokens that is kept free for the response.
+    async fn get_response_tokens(&self, settings: &Settings) -> usize {
+        self.get_effective_parameters(settings)
+            .await
+            .max_tokens
+            .unwrap_or(DEFAULT_RESPONSE_TOKENS)
+    }
+
+    /// Checks whether the conversation and the room that is reserved for the response fit in the
+    /// context window of the model, after leaving out what `Settings.context_strategy` allows.
+    async fn check_context_window(
+        &self,
+        settings: &Settings,
+        model: &Model,
+    ) -> Result<(), PromptError> {
+        let messages = self.messages.lock().await.get_path();
+        let response_tokens = self.get_response_tokens(settings).await;
+        ContextBuilder::new(model, settings.get_context_strategy(), response_tokens)
+            .build(messages)?;
+        Ok(())
+    }
+
+    /// Returns the probability of every token in the message at `message_index`, with the most
+    /// likely alternatives. The list is empty if no log probabilities were requested.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if there is no message at `message_index`.
+    pub async fn get_token_probabilities(
+        &self,
+        message_index: usize,
+    ) -> Result<Vec<TokenProbability>, PromptError> {
         let messages = self.messages.lock().await;
-        messages.iter().flat_map(|message| message.get_content().split(" ")).count()
+        let message = messages
+            .get(message_index)
+            .ok_or(PromptError::InvalidMessageIndex(message_index))?;
+
+        Ok(message
+            .get_logprobs()
+            .iter()
+            .map(|logprob| TokenProbability {
+                token: logprob.token.clone(),
+                probability: logprob.logprob.exp(),
+                alternatives: logprob
+                    .top_logprobs
+                    .iter()
+                    .map(|alternative| Alternative {
+                        token: alternative.token.clone(),
+                        probability: alternative.logprob.exp(),
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// Continues the conversation from another candidate of the message at `message_index`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is locked, or if the message or
+    /// candidate does not exist.
+    pub async fn select_candidate(
+        &self,
+        message_index: usize,
+        candidate_index: usize,
+    ) -> Result<(), PromptError> {
+        if self.is_locked.load(Ordering::SeqCst) {
+            return Err(PromptError::ConversationLocked);
+        }
+
+        let mut messages = self.messages.lock().await;
+        let message = messages
+            .get_mut(message_index)
+            .ok_or(PromptError::InvalidMessageIndex(message_index))?;
+
+        if candidate_index >= message.get_candidates().len() {
+            return Err(PromptError::InvalidCandidate(candidate_index));
+        }
+
+        message.select_candidate(candidate_index);
+        Ok(())
+    }
+
+    /// Returns where every message that is shown stands among its alternatives.
+    pub async fn list_branches(&self) -> Vec<Branches> {
+        self.messages.lock().await.list_branches()
+    }
+
+    /// Shows the alternative `alternative` of the message at `message_index` instead, together
+    /// with the messages that followed it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is locked, or if the message or
+    /// alternative does not exist.
+    pub async fn switch_branch(
+        &self,
+        message_index: usize,
+        alternative: usize,
+    ) -> Result<(), PromptError> {
+        if self.is_locked.load(Ordering::SeqCst) {
+            return Err(PromptError::ConversationLocked);
+        }
+
+        let mut messages = self.messages.lock().await;
+        if message_index >= messages.len() {
+            return Err(PromptError::InvalidMessageIndex(message_index));
+        }
+        if !messages.switch_branch(message_index, alternative) {
+            return Err(PromptError::InvalidBranch(alternative));
+        }
+        Ok(())
+    }
+
+    /// Changes the message at `message_index` with `change`. The results of the tools the message
+    /// called are changed along with it, the API rejects tool results without their call. For the
+    /// same reason a tool result is never changed on its own, the change goes to the message
+    /// that called the tool instead.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is locked, or if the message does
+    /// not exist.
+    async fn change_message(
+        &self,
+        message_index: usize,
+        change: impl Fn(&mut Message),
+    ) -> Result<(), PromptError> {
+        if self.is_locked.load(Ordering::SeqCst) {
+            return Err(PromptError::ConversationLocked);
+        }
+
+        let mut messages = self.messages.lock().await;
+        let message_index = match messages.get(message_index) {
+            Some(message) if *message.get_role() == Role::tool => messages
+                .iter()
+                .take(message_index)
+                .rposition(|message| message.get_tool_calls().is_some())
+                .ok_or(PromptError::InvalidMessageIndex(message_index))?,
+            Some(_) => message_index,
+            None => return Err(PromptError::InvalidMessageIndex(message_index)),
+        };
+        let message = messages.get_mut(message_index).unwrap();
+        change(message);
+
+        if message.get_tool_calls().is_some() {
+            let mut index = message_index + 1;
+            while let Some(tool_result) = messages.get_mut(index) {
+                if *tool_result.get_role() != Role::tool {
+                    break;
+                }
+                change(tool_result);
+                index += 1;
+            }
+        }
+        Ok(())
     }
 
-    pub async fn get_token_count(&self) -> usize {
-        // TODO: Make this count actual tokens instead of an estimate
-        // https://openai.com/pricing
-        self.get_word_count().await * 1000 / 750
+    /// Keeps the message at `message_index` from being sent to the model, while it is still
+    /// shown.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is locked, or if the message does
+    /// not exist.
+    pub async fn set_excluded(
+        &self,
+        message_index: usize,
+        excluded: bool,
+    ) -> Result<(), PromptError> {
+        self.change_message(message_index, |message| message.set_excluded(excluded))
+            .await
+    }
+
+    /// Keeps the message at `message_index` in the conversation when older messages are dropped
+    /// to make it fit in the context window.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is locked, or if the message does
+    /// not exist.
+    pub async fn set_pinned(&self, message_index: usize, pinned: bool) -> Result<(), PromptError> {
+        self.change_message(message_index, |message| message.set_pinned(pinned))
+            .await
+    }
+
+    /// Deletes the message at `message_index`, it is neither shown nor sent anymore.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is locked, or if the message does
+    /// not exist.
+    pub async fn delete_message(&self, message_index: usize) -> Result<(), PromptError> {
+        self.change_message(message_index, |message| message.delete())
+            .await
     }
 
     /// Clears all the messages in this conversation
@@ -118,35 +606,37 @@ impl Conversation {
             return Err(PromptError::ConversationLocked);
         }
 
-        *self.messages.lock().await = vec![];
+        *self.messages.lock().await = MessageTree::default();
         Ok(())
     }
 
     /// Converts this conversation into a `SerializedConversation` that can be converted to JSON.
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error if `self.get_name()` fails.
-    pub async fn serialize(&self, api_key: &str) -> Result<SerializedConversation> {
-        Ok(SerializedConversation {
-            name: self.get_name(api_key).await?,
+    pub async fn serialize(
+        &self,
+        settings: &Settings,
+        client: &reqwest::Client,
+    ) -> SerializedConversation {
+        SerializedConversation {
+            name: self.get_name(settings, client).await,
             id: self.id.load(Ordering::Relaxed),
             messages: self.messages.lock().await.clone(),
             date_created: self.date_created.load(Ordering::Relaxed),
-        })
+            parameter_overrides: self.get_parameters().await,
+            parameters: self.get_effective_parameters(settings).await,
+            response_format: self.get_response_format().await,
+        }
     }
 
-    /// Serializes this conversation and saves it in the data directory.
+    /// Serializes this conversation and saves it in the data directory. A name is generated the
+    /// first time the conversation is saved.
     ///
     /// # Errors
     ///
-    /// This function will return an error if a name cannot be generated, or if the save directory
-    /// cannot be acquired.
-    pub async fn save(&self, api_key: &str) -> Result<()> {
-        *self.name.lock().await = Some(self.generate_name(api_key).await?);
+    /// This function will return an error if the save directory cannot be acquired.
+    pub async fn save(&self, settings: &Settings, client: &reqwest::Client) -> Result<()> {
         let filename = self.id.load(Ordering::Relaxed).to_string();
 
-        let serialized_conversation = self.serialize(api_key).await?;
+        let serialized_conversation = self.serialize(settings, client).await;
         let file_contents = serde_json::to_string(&serialized_conversation)?;
 
         let mut path = Self::get_save_dir().await?;
@@ -159,8 +649,12 @@ impl Conversation {
     pub async fn load(&self, id: u64) -> Result<()> {
         let loaded_conversation = Self::load_serialized(id).await?;
         self.id.store(loaded_conversation.id, Ordering::Relaxed);
+        self.date_created
+            .store(loaded_conversation.date_created, Ordering::Relaxed);
         *self.messages.lock().await = loaded_conversation.messages;
         *self.name.lock().await = Some(loaded_conversation.name);
+        *self.parameters.lock().await = loaded_conversation.parameter_overrides;
+        *self.response_format.lock().await = loaded_conversation.response_format;
         Ok(())
     }
 
@@ -169,43 +663,60 @@ impl Conversation {
         file_path.push(id.to_string());
         let file_path = file_path;
 
-        dbg!(&file_path);
-
         let serialized = fs::read_to_string(file_path).await?;
         let deserialized: SerializedConversation = serde_json::from_str(&serialized)?;
 
         Ok(deserialized)
     }
 
-    pub async fn generate_name(&self, api_key: &str) -> Result<String> {
-        let mut cloned_messages = self.messages.lock().await.clone();
-        cloned_messages.push(Message::new(Role::user, "Write a name for this conversation, it should not be longer than a few words. Do not mention math if the user doesn't. Do not say anything except the name, do not put it in quotes and do not use a period.".into()));
+    /// Has the naming model write a name for this conversation. The request is built like any
+    /// other, so only what fits in the context window of the naming model is sent.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request cannot be made.
+    pub async fn generate_name(
+        &self,
+        settings: &Settings,
+        client: &reqwest::Client,
+    ) -> Result<String> {
+        let mut messages = self.messages.lock().await.get_path();
+        messages.push(Message::new(Role::user, NAMING_PROMPT.into()));
+
+        let model = settings.get_naming_model();
+        let context =
+            ContextBuilder::new(&model, ContextStrategy::DropOldest, DEFAULT_RESPONSE_TOKENS)
+                .build(messages)
+                .map_err(PromptError::from)?;
 
-        let mut stream = Request::new(cloned_messages, "gpt-3.5-turbo")
-            .do_request(api_key)
+        let provider = settings.get_provider(client)?;
+        let request = Request::new(context.messages, model.get_id());
+        let stream = Self::shape_for_model(request, &model, settings)
+            .do_request(provider, settings.get_retry_policy())
             .context("Failed to make api request while generating name for conversation")?;
 
-        let mut name = String::new();
-        while let Some(Ok(delta)) = stream.next().await {
-            match delta {
-                MessageDelta::Delta(delta) => name.push_str(&delta),
-                _ => continue,
-            }
-        }
-        let name = name;
+        let (name, _) = Self::read_text(stream, settings.get_network().get_read_timeout())
+            .await
+            .context("Failed to generate a name for the conversation")?;
         Ok(name)
     }
 
-    pub async fn get_name(&self, api_key: &str) -> Result<String> {
+    /// Returns the name of this conversation, a name is generated the first time. When that
+    /// fails, the default name is returned and a name is generated again the next time.
+    pub async fn get_name(&self, settings: &Settings, client: &reqwest::Client) -> String {
         let mut current_name = self.name.lock().await;
 
         if let Some(name) = &*current_name {
-            return Ok(name.into());
+            return name.into();
         }
 
-        let new_name = self.generate_name(api_key).await?;
-        *current_name = Some(new_name.clone());
-        Ok(new_name)
+        match self.generate_name(settings, client).await {
+            Ok(new_name) => {
+                *current_name = Some(new_name.clone());
+                new_name
+            }
+            Err(_) => DEFAULT_NAME.to_string(),
+        }
     }
 
     /// Returns the directory that conversations should be saved in. Automatically creates it if it
@@ -233,6 +744,18 @@ impl Conversation {
         Ok(data_dir)
     }
 
+    /// Returns the directory that the attachments of the conversation with `id` are stored in.
+    /// It is not created until the first attachment is imported.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `Self::get_save_dir()` fails.
+    pub async fn get_attachment_dir(id: u32) -> Result<PathBuf> {
+        let mut attachment_dir = Self::get_save_dir().await?;
+        attachment_dir.push(format!("{}_attachments", id));
+        Ok(attachment_dir)
+    }
+
     pub async fn list_conversations() -> Result<Vec<SerializedConversation>> {
         let mut conversations: Vec<SerializedConversation> = vec![];
         for id in Self::get_conversation_ids().await? {
@@ -267,137 +790,865 @@ impl Conversation {
         Ok(ids)
     }
 
-    /// Submits a prompt to the conversation, requests a completion from the OpenAI api and spawns
+    /// Submits a prompt to the conversation, requests a completion from the configured provider and spawns
     /// a task that streams
-    /// the response. Every time a chunk is received, the `add_message_content` event is fired on
-    /// the window.
+    /// the response. The prompt and the empty answer are sent with the `refresh_messages` event,
+    /// and taken back out if the response can't be started. A request that fails after that is
+    /// stored as an error. Every time a chunk is received, the `add_message_content` event is
+    /// fired on the window.
     ///
     /// # Errors
     ///
-    /// This function will return an error if the request cannot be made.
+    /// This function will return an error if the response cannot be started.
     pub async fn prompt(
         &self,
         prompt: &str,
-        api_key: &str,
-        model: Model,
+        image_paths: &[PathBuf],
+        choice_count: usize,
+        settings: &Settings,
+        model: &Model,
+        client: &reqwest::Client,
         window: &tauri::Window,
-        cancel_state: CancelState
     ) -> Result<()> {
-        // Check if conversation is locked
-        if self.is_locked.load(Ordering::SeqCst) {
-            return Err(PromptError::ConversationLocked.into());
-        };
+        let lock = self.lock_for_response()?;
+
+        if !image_paths.is_empty() && !model.supports_vision() {
+            return Err(PromptError::VisionNotSupported(model.get_id().to_string()).into());
+        }
+        let choice_count = Self::get_choice_count(choice_count, settings, client)?;
 
+        // Copy the images into the save directory, so the conversation still works if the
+        // originals are moved
+        let attachment_dir = Self::get_attachment_dir(self.get_id()).await?;
+        let mut attachments: Vec<Attachment> = vec![];
+        for image_path in image_paths {
+            attachments.push(Attachment::import(image_path, &attachment_dir).await?);
+        }
+
+        let events = EventEmitter::new(window, self.get_id());
         {
             let mut messages = self.messages.lock().await;
             // Add the prompt to the messages
-            messages.push(Message::new(Role::user, prompt.into()));
+            messages.push(Message::new(Role::user, prompt.into()).with_attachments(attachments));
 
             // Add empty assistant message that the deltas will be applied to
-            messages.push(Message::new(Role::assistant, "".into()));
+            messages
+                .push(Message::new(Role::assistant, "".into()).with_candidate_count(choice_count));
+            events.emit("refresh_messages", messages.get_path());
         }
 
-        // Start background task that makes the openai request and applies the received deltas to
-        // the messages.
+        let result = match self.check_context_window(settings, model).await {
+            Ok(()) => {
+                self.start_response(choice_count, false, settings, model, client, window)
+                    .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        if result.is_err() {
+            // Remove the prompt and the empty answer again
+            let mut messages = self.messages.lock().await;
+            messages.pop();
+            messages.pop();
+            events.emit("refresh_messages", messages.get_path());
+        }
+
+        result?;
+        lock.hand_over();
+        Ok(())
+    }
+
+    /// Continues the last answer where it was cut off. The new content is appended to the answer
+    /// in place, just like the deltas of a normal response.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the last message is not an answer or if the request
+    /// cannot be made.
+    pub async fn continue_generation(
+        &self,
+        settings: &Settings,
+        model: &Model,
+        client: &reqwest::Client,
+        window: &tauri::Window,
+    ) -> Result<()> {
+        let lock = self.lock_for_response()?;
 
         {
-            let is_locked = Arc::clone(&self.is_locked);
-            let messages = Arc::clone(&self.messages);
-            let mut delta_stream =
-                gpt::Request::new(self.messages.lock().await.clone(), model.to_string()).do_request(api_key)?;
-            let window = window.clone();
-            let api_key = api_key.to_string();
-            let conversation = self.clone();
-            let input_token_count = self.get_token_count().await;
-
-            tokio::spawn(async move {
-                is_locked.store(true, Ordering::SeqCst);
-                window.emit("lock", true).unwrap();
-
-                let mut output = String::new();
-
-                // Await next message with a timeout of 5 seconds.
-                println!("Waiting for next thing in stream");
-                loop {
-                    let content = match timeout(Duration::from_secs(5), delta_stream.next()).await {
-                        Ok(delta) => match delta {
-                            Some(delta) => match delta {
-                                Ok(delta) => match delta {
-                                    MessageDelta::Delta(delta) => {
-                                        if cancel_state.receive_cancel() {
-                                            break;
-                                        }
-                                        delta
-                                    }, // We actually got some message content
-                                    MessageDelta::Role(_) => continue,
-                                    MessageDelta::NoData => continue,
-                                    MessageDelta::Done => {
-                                        println!("Got done message");
-                                        break;
-                                    },
-                                },
-                                Err(err) => match err {
-                                    gpt::StreamError::StreamReadFailed(err) => {
-                                        eprintln!("Failed to read from stream");
-                                        eprintln!("{err}");
-                                        break;
-                                    },
-                                    gpt::StreamError::InvalidJson(err) => {
-                                        eprintln!("Got invalid json from API");
-                                        eprintln!("{err}");
-                                        break;
-                                    },
-                                    gpt::StreamError::InvalidEvent => {
-                                        eprintln!("Got unknown/invalid event from API");
-                                        break;
-                                    },
-                                },
-                            },
-                            None => {
-                                eprintln!("Stream returned None, assuming it ended");
-                                break;
-                            },
-                        },
-                        Err(_) => {
-                            println!("OpenAI API took too long to respond");
-                            break;
-                        },
-                    };
-                    println!("Got thing in stream");
+            let messages = self.messages.lock().await;
+            match messages.last() {
+                Some(message) if *message.get_role() == Role::assistant => {}
+                _ => return Err(PromptError::NothingToContinue.into()),
+            }
+        }
+        self.check_context_window(settings, model).await?;
 
-                    println!("Locking messages");
-                    let mut messages = messages.lock().await;
-                    messages.last_mut().unwrap().add_content(&content);
-                    output += &content;
+        self.start_response(1, true, settings, model, client, window)
+            .await?;
+        lock.hand_over();
+        Ok(())
+    }
 
-                    window
-                        .emit("add_message_content", content.to_owned())
-                        .unwrap();
-                }
+    /// Adds an alternative with `content` to the prompt at `message_index` and requests an answer
+    /// to it. The original prompt and everything after it stay behind as another branch.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is locked, if the message is not a
+    /// prompt or if the request cannot be made.
+    pub async fn edit_message(
+        &self,
+        message_index: usize,
+        content: String,
+        settings: &Settings,
+        model: &Model,
+        client: &reqwest::Client,
+        window: &tauri::Window,
+    ) -> Result<()> {
+        let lock = self.lock_for_response()?;
+
+        let original_messages = {
+            let mut messages = self.messages.lock().await;
+            let original_messages = messages.clone();
+            let message = messages
+                .get(message_index)
+                .ok_or(PromptError::InvalidMessageIndex(message_index))?;
+            if *message.get_role() != Role::user {
+                return Err(PromptError::NotAPrompt(message_index).into());
+            }
+            let attachments = message.get_attachments().clone();
+
+            messages.split_off(message_index);
+            messages.push(Message::new(Role::user, content).with_attachments(attachments));
+            original_messages
+        };
+
+        self.respond_after(
+            message_index,
+            original_messages,
+            settings,
+            model,
+            client,
+            window,
+        )
+        .await?;
+        lock.hand_over();
+        Ok(())
+    }
+
+    /// Requests a new answer to the last prompt, the last answer, including its tool calls and
+    /// errors, stays behind as an alternative. What the old answer cost is kept as an earlier
+    /// attempt.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is locked, if there is no prompt
+    /// to answer or if the request cannot be made.
+    pub async fn regenerate(
+        &self,
+        settings: &Settings,
+        model: &Model,
+        client: &reqwest::Client,
+        window: &tauri::Window,
+    ) -> Result<()> {
+        let lock = self.lock_for_response()?;
+
+        let (prompt_index, original_messages) = {
+            let messages = self.messages.lock().await;
+            let prompt_index = messages
+                .iter()
+                .rposition(|message| *message.get_role() == Role::user && !message.is_deleted())
+                .ok_or(PromptError::NothingToRegenerate)?;
+            (prompt_index, messages.clone())
+        };
+
+        self.respond_after(
+            prompt_index,
+            original_messages,
+            settings,
+            model,
+            client,
+            window,
+        )
+        .await?;
+        lock.hand_over();
+        Ok(())
+    }
+
+    /// Replaces everything after the prompt at `prompt_index` with a new answer. The costs of
+    /// the answers that are replaced are carried over to the new one, so the cost of the answer
+    /// includes every attempt. When the new answer can't be started, the conversation is
+    /// restored to `original_messages`.
+    async fn respond_after(
+        &self,
+        prompt_index: usize,
+        original_messages: MessageTree,
+        settings: &Settings,
+        model: &Model,
+        client: &reqwest::Client,
+        window: &tauri::Window,
+    ) -> Result<()> {
+        let events = EventEmitter::new(window, self.get_id());
+        // Ask for as many choices as the old answer had
+        let choice_count = self
+            .messages
+            .lock()
+            .await
+            .get(prompt_index + 1)
+            .map(|answer| answer.get_candidates().len().max(1))
+            .unwrap_or(1);
+        let choice_count = match Self::get_choice_count(choice_count, settings, client) {
+            Ok(choice_count) => choice_count,
+            Err(e) => {
+                *self.messages.lock().await = original_messages;
+                return Err(e);
+            }
+        };
+        {
+            let mut messages = self.messages.lock().await;
+            let discarded = messages.split_off(prompt_index + 1);
+
+            // An answer that took several tool rounds counts as a single attempt
+            let mut attempt_costs: Vec<f32> = discarded
+                .iter()
+                .flat_map(|message| message.get_attempt_costs())
+                .cloned()
+                .collect();
+            if discarded.iter().any(|message| message.get_cost().is_some()) {
+                attempt_costs.push(
+                    discarded
+                        .iter()
+                        .filter_map(|message| message.get_cost())
+                        .sum(),
+                );
+            }
+
+            messages.push(
+                Message::new(Role::assistant, "".into())
+                    .with_candidate_count(choice_count)
+                    .with_attempt_costs(attempt_costs),
+            );
+        }
+
+        let result = match self.check_context_window(settings, model).await {
+            Ok(()) => {
+                self.start_response(choice_count, false, settings, model, client, window)
+                    .await
+            }
+            Err(e) => Err(e.into()),
+        };
+
+        let mut messages = self.messages.lock().await;
+        if result.is_err() {
+            *messages = original_messages;
+        }
+        events.emit("refresh_messages", messages.get_path());
+        result
+    }
+
+    /// Returns how many choices are requested when the user asks for `choice_count`, providers
+    /// that can't generate several choices at once generate a single one. The empty answer needs
+    /// a candidate for every choice that is streamed into it, and for none that isn't.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the provider cannot be created.
+    fn get_choice_count(
+        choice_count: usize,
+        settings: &Settings,
+        client: &reqwest::Client,
+    ) -> Result<usize> {
+        Ok(
+            match settings.get_provider(client)?.supports_choice_count() {
+                true => choice_count,
+                false => 1,
+            },
+        )
+    }
+
+    /// Requests a completion for the last message and spawns a task that streams the response.
+    /// Every time a chunk is received, the `add_message_content` event is fired on the window.
+    /// When the model calls tools, they are run and their results are sent back in a new
+    /// request, until the model produces a final answer. Answers that are cut off by the token
+    /// limit are continued up to `Settings.auto_continue_limit` times. The requests are made by
+    /// the task, a request that fails ends the response with an error message. The conversation
+    /// has to be locked already, the task unlocks it when it is done.
+    async fn start_response(
+        &self,
+        choice_count: usize,
+        continuation: bool,
+        settings: &Settings,
+        model: &Model,
+        client: &reqwest::Client,
+        window: &tauri::Window,
+    ) -> Result<()> {
+        let is_locked = Arc::clone(&self.is_locked);
+        let messages = Arc::clone(&self.messages);
+        let model = model.clone();
+        let provider = settings.get_provider(client)?;
+        let tools = ToolRegistry::with_builtin_tools();
+        let events = EventEmitter::new(window, self.get_id());
+        let cancel_state = self.cancel_state.clone();
+        let settings = settings.clone();
+        let client = client.clone();
+        let read_timeout = settings.get_network().get_read_timeout();
+        let reasoning = model.is_reasoning_model();
+        let conversation = self.clone();
+        // Everything from the answer on is created by this response
+        let answer_index = self.messages.lock().await.len() - 1;
+
+        tokio::spawn(async move {
+            events.emit("lock", true);
 
-                println!("Stream ended");
+            let mut continuation = continuation;
+            // Only the first request asks for several choices, tool rounds and continuations
+            // extend the selected one
+            let mut choice_count = choice_count;
+            let mut tool_rounds = 0;
+            let mut continue_rounds = 0;
+            loop {
+                // Built in here, because summarizing the messages that are left out is a request
+                // of its own
+                let input_token_count = conversation.get_token_count(&model).await;
+                let mut delta_stream = match conversation
+                    .build_request(&settings, &model, &provider, &tools, continuation, &events)
+                    .await
+                    .and_then(|request| {
+                        Ok(request
+                            .with_choice_count(choice_count)
+                            .do_request(Arc::clone(&provider), settings.get_retry_policy())?)
+                    }) {
+                    Ok(delta_stream) => delta_stream,
+                    Err(err) => {
+                        let error = ApiError::Other(format!("{err:#}"));
+                        Self::fail_response(&messages, &events, error).await;
+                        break;
+                    }
+                };
+                choice_count = 1;
 
-                let cost = model.calculate_cost(input_token_count, Self::count_tokens(&output));
+                let response = Self::read_stream(
+                    &mut delta_stream,
+                    &messages,
+                    &events,
+                    &cancel_state,
+                    continuation,
+                    read_timeout,
+                    reasoning,
+                )
+                .await;
 
+                // Prefer the token counts reported by the provider, our own estimate can be way
+                // off for code and LaTeX. Reasoning tokens are never streamed, so without a
+                // reported usage only the reported reasoning tokens can be added.
+                let estimated = response.usage.prompt_tokens.is_none()
+                    || response.usage.completion_tokens.is_none()
+                    || !model.has_prices();
+                let reasoning_tokens = response.usage.reasoning_tokens.unwrap_or(0);
+                let cost = model.calculate_cost(
+                    response.usage.prompt_tokens.unwrap_or(input_token_count),
+                    response.usage.cached_tokens.unwrap_or(0),
+                    response.usage.completion_tokens.unwrap_or_else(|| {
+                        tokenizer::count_tokens(&response.output, model.get_encoding())
+                            + reasoning_tokens
+                    }),
+                );
+
+                {
+                    let mut messages = messages.lock().await;
+                    let message = messages.last_mut().unwrap();
+                    message.add_cost(cost, estimated);
+                    message.add_reasoning_tokens(reasoning_tokens);
+                    message.set_finish_reason(response.finish_reason.clone());
+                }
+                if let Some(finish_reason) = &response.finish_reason {
+                    events.emit("finish_reason", finish_reason);
+                }
+
+                if let Some(error) = response.error {
+                    Self::fail_response(&messages, &events, error).await;
+                    break;
+                } else if !response.cancelled
+                    && !response.tool_calls.is_empty()
+                    && tool_rounds < MAX_TOOL_ROUNDS
                 {
+                    tool_rounds += 1;
+                    continuation = false;
+
+                    let mut messages = messages.lock().await;
+                    messages
+                        .last_mut()
+                        .unwrap()
+                        .set_tool_calls(response.tool_calls.clone());
+
+                    for tool_call in &response.tool_calls {
+                        let result = tools.call(tool_call);
+                        messages.push(Message::tool_result(tool_call.id.clone(), result));
+                    }
+
+                    // Add empty assistant message for the response to the tool results
+                    messages.push(Message::new(Role::assistant, "".into()));
+                    events.emit("refresh_messages", messages.get_path());
+                } else if !response.cancelled && !response.tool_calls.is_empty() {
+                    // The calls are kept, so it is clear what the model wanted to do
                     let mut messages = messages.lock().await;
-                    messages.last_mut().unwrap().set_cost(cost);
+                    messages
+                        .last_mut()
+                        .unwrap()
+                        .set_tool_calls(response.tool_calls.clone());
+                    for tool_call in &response.tool_calls {
+                        messages.push(Message::tool_result(
+                            tool_call.id.clone(),
+                            TOOL_LIMIT_RESULT.to_string(),
+                        ));
+                    }
+                    messages.push(Message::new(
+                        Role::error,
+                        format!(
+                            "The model kept calling tools, the calls after {} rounds were not run",
+                            MAX_TOOL_ROUNDS
+                        ),
+                    ));
+                    events.emit("refresh_messages", messages.get_path());
+                    break;
+                } else if !response.cancelled
+                    && response.finish_reason == Some(FinishReason::Length)
+                    && continue_rounds < settings.get_auto_continue_limit()
+                {
+                    continue_rounds += 1;
+                    continuation = true;
+                } else {
+                    let content = messages
+                        .lock()
+                        .await
+                        .last()
+                        .unwrap()
+                        .get_content()
+                        .to_string();
+                    if let (Some(response_format), false) =
+                        (conversation.get_response_format().await, response.cancelled)
+                    {
+                        let errors = response_format.validate(&content);
+                        let valid = errors.is_empty();
+                        if !valid {
+                            // Stored like the API errors, so it is still shown after a reload
+                            let mut messages = messages.lock().await;
+                            messages.push(Message::new(
+                                Role::error,
+                                format!(
+                                    "The response does not match the response format:\n{}",
+                                    errors.join("\n")
+                                ),
+                            ));
+                            events.emit("refresh_messages", messages.get_path());
+                        }
+                        events.emit("validation", ValidationResult { valid, errors });
+                    }
+                    break;
+                }
+            }
+
+            {
+                let messages = messages.lock().await;
+                let mut cost = Cost {
+                    dollars: 0.0,
+                    estimated: false,
+                    reasoning_tokens: 0,
+                };
+                for message in messages.iter().skip(answer_index) {
+                    cost.dollars += message.get_cost().unwrap_or(0.0);
+                    cost.estimated |= message.is_cost_estimated();
+                    cost.reasoning_tokens += message.get_reasoning_tokens();
                 }
+                // The messages show what they cost themselves
+                events.emit("refresh_messages", messages.get_path());
+                events.emit("cost", cost);
+            }
+
+            let _ = conversation.save(&settings, &client).await;
+            is_locked.store(false, Ordering::SeqCst);
+            events.emit("lock", false);
+        });
+
+        Ok(())
+    }
+
+    /// Ends a response that failed with `error`. The error is stored like a message, so it is
+    /// still shown after a reload, and the `api_error` event is emitted.
+    async fn fail_response(
+        messages: &Arc<Mutex<MessageTree>>,
+        events: &EventEmitter,
+        error: ApiError,
+    ) {
+        let mut messages = messages.lock().await;
+        let mut error_message = Message::new(Role::error, error.to_string());
+        // Don't leave an empty answer behind, it would be sent with the next prompt. What it and
+        // the attempts before it cost is kept on the error.
+        let last_message = messages.last().unwrap();
+        if last_message.get_content().is_empty() && last_message.get_tool_calls().is_none() {
+            let answer = messages.pop().unwrap();
+            if let Some(cost) = answer.get_cost() {
+                error_message.add_cost(cost, answer.is_cost_estimated());
+            }
+            error_message.add_reasoning_tokens(answer.get_reasoning_tokens());
+            error_message = error_message.with_attempt_costs(answer.get_attempt_costs().clone());
+        }
+        messages.push(error_message);
 
-                let _ = conversation.save(&api_key).await;
-                is_locked.store(false, Ordering::SeqCst);
-                window.emit("lock", false).unwrap();
-                println!("Got cost: {}", cost);
-                window.emit("cost", cost).unwrap(); // Send the cost to the client
+        events.emit(
+            "api_error",
+            ApiErrorEvent {
+                message: error.to_string(),
+                error,
+            },
+        );
+        events.emit("refresh_messages", messages.get_path());
+    }
+
+    /// Builds a request for the current messages with everything the settings and this
+    /// conversation specify. A `continuation` request asks the model to continue the last
+    /// answer instead of answering the last prompt. When the messages don't fit in the context
+    /// window, the oldest are left out or summarized and the `context_trimmed` event is emitted.
+    /// Messages that can't be summarized are left out, the event says why.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the messages don't fit in the context window, or if
+    /// an attached image cannot be read.
+    async fn build_request(
+        &self,
+        settings: &Settings,
+        model: &Model,
+        provider: &Arc<dyn ChatProvider>,
+        tools: &ToolRegistry,
+        continuation: bool,
+        events: &EventEmitter,
+    ) -> Result<Request> {
+        let mut messages = self.messages.lock().await.get_path();
+        if continuation {
+            // Not every provider can continue an assistant message on its own, so we ask for it
+            messages.push(Message::new(Role::user, CONTINUE_PROMPT.to_string()));
+        }
+
+        let strategy = settings.get_context_strategy();
+        let response_tokens = self.get_response_tokens(settings).await;
+        let mut context = ContextBuilder::new(model, strategy, response_tokens)
+            .with_continuation(continuation)
+            .build(messages)
+            .map_err(PromptError::from)?;
+        if strategy == ContextStrategy::Summarize && !context.left_out.is_empty() {
+            // Without the summary the request still fits, the messages are just dropped
+            match self
+                .summarize(&context.left_out, settings, model, provider)
+                .await
+            {
+                Ok(summary) => context.add_summary(summary, model),
+                Err(err) => context.set_summary_failed(format!("{err:#}")),
+            }
+        }
+        if let Some(trimmed) = &context.trimmed {
+            events.emit("context_trimmed", trimmed);
+        }
+        let mut messages = context.messages;
+
+        let attachment_dir = Self::get_attachment_dir(self.get_id()).await?;
+        for attachment in messages
+            .iter_mut()
+            .flat_map(|message| message.get_attachments_mut())
+        {
+            attachment.load_data(&attachment_dir).await?;
+        }
+
+        let mut request = Request::new(messages, model.get_id())
+            .with_parameters(self.get_effective_parameters(settings).await);
+
+        // The continuation of a JSON answer is not a JSON document on its own
+        if !continuation {
+            request = request.with_response_format(self.get_response_format().await);
+        }
+
+        if provider.supports_logprobs() {
+            request = request.with_logprobs(settings.get_top_logprobs());
+        }
+
+        if settings.get_enable_tools() && provider.supports_tools() && model.supports_tools() {
+            request = request.with_tools(tools.get_definitions());
+        }
+
+        Ok(Self::shape_for_model(request, model, settings))
+    }
+
+    /// Shapes `request` the way `model` needs it, see `Request::for_reasoning_model` and
+    /// `Request::without_system_messages`.
+    fn shape_for_model(request: Request, model: &Model, settings: &Settings) -> Request {
+        let mut request = request;
+        if model.is_reasoning_model() {
+            request = request.for_reasoning_model(settings.get_reasoning_effort());
+        }
+        if !model.supports_system_messages() {
+            request = request.without_system_messages();
+        }
+        request
+    }
+
+    /// Has the model summarize `messages`, which are left out of a request. The summary is reused
+    /// as long as the same messages are left out. When the messages don't fit in the context
+    /// window themselves, only the newest are summarized. What the summary cost is added to the
+    /// answer it is made for, the last message, which passes it on to its error if the response
+    /// fails.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if not even the newest message fits, or if the request
+    /// cannot be made.
+    async fn summarize(
+        &self,
+        messages: &[Message],
+        settings: &Settings,
+        model: &Model,
+        provider: &Arc<dyn ChatProvider>,
+    ) -> Result<String> {
+        let mut hasher = DefaultHasher::new();
+        for message in messages {
+            message.get_content().hash(&mut hasher);
+        }
+        let key = hasher.finish();
+        if let Some((summarized, summary)) = &*self.summary.lock().await {
+            if *summarized == key {
+                return Ok(summary.clone());
+            }
+        }
+
+        // A transcript in a single prompt works with every provider, tool results can't be sent
+        // without their calls and images without their data.
+        let encoding = model.get_encoding();
+        let budget = model.get_context_window().map(|context_window| {
+            context_window.saturating_sub(
+                SUMMARY_TOKENS
+                    + tokenizer::count_tokens(SYSTEM_PROMPT, encoding)
+                    + tokenizer::count_tokens(SUMMARY_PROMPT, encoding),
+            )
+        });
+        let mut lines: Vec<String> = vec![];
+        let mut token_count = 0;
+        for message in messages.iter().rev() {
+            let line = format!("{}: {}", message.get_role().as_str(), message.get_content());
+            token_count += tokenizer::count_tokens(&line, encoding);
+            if matches!(budget, Some(budget) if token_count > budget) {
+                break;
+            }
+            lines.push(line);
+        }
+        if lines.is_empty() {
+            return Err(anyhow!("The messages are too long to summarize"));
+        }
+        lines.reverse();
+        let transcript = lines.join("\n\n");
+        let prompt = Message::new(Role::user, format!("{transcript}\n\n{SUMMARY_PROMPT}"));
+        let prompt_token_count = tokenizer::count_message_tokens(&prompt, encoding);
+
+        let request =
+            Request::new(vec![prompt], model.get_id()).with_parameters(SamplingParameters {
+                max_tokens: Some(SUMMARY_TOKENS),
+                ..Default::default()
             });
+        let stream = Self::shape_for_model(request, model, settings)
+            .do_request(Arc::clone(provider), settings.get_retry_policy())
+            .context("Failed to make api request while summarizing the conversation")?;
+        let (summary, usage) =
+            Self::read_text(stream, settings.get_network().get_read_timeout()).await?;
+
+        let estimated = usage.prompt_tokens.is_none()
+            || usage.completion_tokens.is_none()
+            || !model.has_prices();
+        let cost = model.calculate_cost(
+            usage.prompt_tokens.unwrap_or(prompt_token_count),
+            usage.cached_tokens.unwrap_or(0),
+            usage
+                .completion_tokens
+                .unwrap_or_else(|| tokenizer::count_tokens(&summary, encoding)),
+        );
+        if let Some(answer) = self.messages.lock().await.last_mut() {
+            answer.add_cost(cost, estimated);
         }
 
-        Ok(())
+        *self.summary.lock().await = Some((key, summary.clone()));
+        Ok(summary)
     }
 
-    fn count_tokens(string: &str) -> usize {
-        string.split(' ').count() * 1000 / 750
+    /// Collects the content of the first choice of a request that isn't shown while it streams,
+    /// like a name or a summary, together with the usage. Like `read_stream`, nothing arriving
+    /// within `read_timeout`, plus the delay of a retry, counts as the network being down.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the stream fails or times out.
+    async fn read_text(
+        mut delta_stream: DeltaStream,
+        read_timeout: Duration,
+    ) -> Result<(String, Usage)> {
+        let mut text = String::new();
+        let mut usage = Usage::default();
+        let mut retry_delay = Duration::ZERO;
+        loop {
+            let delta = timeout(read_timeout + retry_delay, delta_stream.next())
+                .await
+                .map_err(|_| ApiError::NetworkDown)?;
+            retry_delay = Duration::ZERO;
+            match delta {
+                Some(Ok(MessageDelta::Delta(0, delta))) => text.push_str(&delta),
+                Some(Ok(MessageDelta::Usage(new_usage))) => usage.update(new_usage),
+                Some(Ok(MessageDelta::Retrying { delay_ms, .. })) => {
+                    retry_delay = Duration::from_millis(delay_ms)
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok((text, usage)),
+            }
+        }
     }
+
+    /// Reads deltas from `delta_stream` and applies them to the last message until the stream
+    /// ends, fails or is cancelled. Every chunk of content is emitted with the
+    /// `add_message_content` event. The content of a `continuation` is added to the selected
+    /// choice of the message. The stream is considered dead when nothing arrives within
+    /// `read_timeout`, except while a `reasoning` model is thinking. Then the `thinking` event is
+    /// emitted every `THINKING_INTERVAL` until content arrives or `MAX_THINKING_TIME` has passed.
+    async fn read_stream(
+        delta_stream: &mut DeltaStream,
+        messages: &Arc<Mutex<MessageTree>>,
+        events: &EventEmitter,
+        cancel_state: &CancelState,
+        continuation: bool,
+        read_timeout: Duration,
+        reasoning: bool,
+    ) -> StreamResponse {
+        let mut response = StreamResponse::default();
+        let started = Instant::now();
+        let mut received_content = false;
+
+        // Await next message with a timeout, plus the delay of a retry.
+        let mut retry_delay = Duration::ZERO;
+        loop {
+            let thinking = reasoning && !received_content;
+            let next_delta = match thinking {
+                true => timeout(THINKING_INTERVAL, delta_stream.next()),
+                false => timeout(read_timeout + retry_delay, delta_stream.next()),
+            };
+            retry_delay = Duration::ZERO;
+            let (index, content) = match next_delta.await {
+                Ok(delta) => match delta {
+                    Some(delta) => match delta {
+                        Ok(delta) => match delta {
+                            MessageDelta::Delta(index, delta) => {
+                                if cancel_state.receive_cancel() {
+                                    response.cancelled = true;
+                                    break;
+                                }
+                                received_content = true;
+                                (index, delta)
+                            } // We actually got some message content
+                            MessageDelta::Role(_) => continue,
+                            MessageDelta::Usage(new_usage) => {
+                                response.usage.update(new_usage);
+                                continue;
+                            }
+                            MessageDelta::SystemFingerprint(system_fingerprint) => {
+                                let mut messages = messages.lock().await;
+                                messages
+                                    .last_mut()
+                                    .unwrap()
+                                    .set_system_fingerprint(system_fingerprint);
+                                continue;
+                            }
+                            MessageDelta::ToolCall(tool_call_delta) => {
+                                received_content = true;
+                                tool_call_delta.apply(&mut response.tool_calls);
+                                continue;
+                            }
+                            MessageDelta::Logprobs(index, logprobs) => {
+                                let mut messages = messages.lock().await;
+                                let message = messages.last_mut().unwrap();
+                                let index = match continuation {
+                                    true => message.get_selected_candidate(),
+                                    false => index,
+                                };
+                                message.add_candidate_logprobs(index, logprobs);
+                                continue;
+                            }
+                            MessageDelta::FinishReason(index, finish_reason) => {
+                                if index == 0 {
+                                    response.finish_reason = Some(finish_reason);
+                                }
+                                continue;
+                            }
+                            MessageDelta::Retrying { attempt, delay_ms } => {
+                                if cancel_state.receive_cancel() {
+                                    response.cancelled = true;
+                                    break;
+                                }
+                                retry_delay = Duration::from_millis(delay_ms);
+                                events.emit("retrying", Retrying { attempt, delay_ms });
+                                continue;
+                            }
+                            MessageDelta::NoData => continue,
+                            MessageDelta::Done => break,
+                        },
+                        Err(err) => {
+                            response.error = Some(err.into());
+                            break;
+                        }
+                    },
+                    // The stream ended without a `Done`
+                    None => break,
+                },
+                Err(_) if thinking && started.elapsed() < MAX_THINKING_TIME => {
+                    if cancel_state.receive_cancel() {
+                        response.cancelled = true;
+                        break;
+                    }
+                    let elapsed_ms = started.elapsed().as_millis() as u64;
+                    events.emit("thinking", Thinking { elapsed_ms });
+                    continue;
+                }
+                Err(_) => {
+                    response.error = Some(ApiError::NetworkDown);
+                    break;
+                }
+            };
+            let mut messages = messages.lock().await;
+            let message = messages.last_mut().unwrap();
+            let index = match continuation {
+                true => message.get_selected_candidate(),
+                false => index,
+            };
+            message.add_candidate_content(index, &content);
+            response.output += &content;
+
+            // The selected choice is what gets shown when there is only one
+            if index == message.get_selected_candidate() {
+                events.emit("add_message_content", content.to_owned());
+            }
+            events.emit("add_candidate_content", CandidateContent { index, content });
+        }
+
+        response
+    }
+}
+
+fn deserialize_messages<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<MessageTree, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredMessages {
+        Flat(Vec<Message>),
+        Tree(MessageTree),
+    }
+
+    Ok(match StoredMessages::deserialize(deserializer)? {
+        StoredMessages::Flat(messages) => messages.into(),
+        StoredMessages::Tree(tree) => tree,
+    })
 }
 
 #[derive(Serialize, Deserialize)]
@@ -405,5 +1656,184 @@ pub struct SerializedConversation {
     name: String,
     id: u32,
     date_created: u64,
-    messages: Vec<Message>,
+    /// Every branch of the conversation. Older versions stored only a list of messages, which is
+    /// loaded as a tree without alternatives.
+    #[serde(deserialize_with = "deserialize_messages")]
+    messages: MessageTree,
+    /// This conversation's overrides for the sampling parameters in the settings, they are
+    /// restored when the conversation is loaded.
+    #[serde(default)]
+    parameter_overrides: SamplingParameters,
+    /// The sampling parameters that were in effect when the conversation was saved, so it can be
+    /// reproduced later. Older versions only stored these.
+    #[serde(default)]
+    parameters: SamplingParameters,
+    /// The response format, including the schema, that the answers were validated against.
+    #[serde(default)]
+    response_format: Option<ResponseFormat>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::{FunctionCall, ToolCall};
+    use crate::providers::ProviderKind;
+
+    #[tokio::test]
+    async fn streams_every_choice_into_its_candidate() {
+        let messages = Arc::new(Mutex::new(MessageTree::from(vec![
+            Message::new(Role::user, "Hi".into()),
+            Message::new(Role::assistant, "".into()).with_candidate_count(2),
+        ])));
+        let mut delta_stream: DeltaStream = Box::pin(tokio_stream::iter(vec![
+            Ok(MessageDelta::Delta(0, "Hello".into())),
+            Ok(MessageDelta::Delta(1, "Hey".into())),
+            Ok(MessageDelta::FinishReason(1, FinishReason::Length)),
+            Ok(MessageDelta::Delta(0, " there".into())),
+            Ok(MessageDelta::FinishReason(0, FinishReason::Stop)),
+            Ok(MessageDelta::Done),
+        ]));
+
+        let response = Conversation::read_stream(
+            &mut delta_stream,
+            &messages,
+            &EventEmitter::detached(0),
+            &CancelState::new(),
+            false,
+            Duration::from_secs(1),
+            false,
+        )
+        .await;
+
+        // Only the finish reason of the first choice counts, every choice is billed
+        assert_eq!(response.finish_reason, Some(FinishReason::Stop));
+        assert_eq!(response.output.len(), "Hello there".len() + "Hey".len());
+        let messages = messages.lock().await;
+        let answer = messages.last().unwrap();
+        assert_eq!(answer.get_candidates(), &vec!["Hello there", "Hey"]);
+        assert_eq!(answer.get_content(), "Hello there");
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_default_name() {
+        let conversation = Conversation::new();
+        // Without a key the request can't be made
+        let settings = Settings::_default();
+
+        let name = conversation
+            .get_name(&settings, &reqwest::Client::new())
+            .await;
+
+        assert_eq!(name, DEFAULT_NAME);
+        // The next save tries again
+        assert!(conversation.name.lock().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_failed_answer_leaves_its_costs_on_the_error() {
+        let mut answer = Message::new(Role::assistant, "".into()).with_attempt_costs(vec![0.5]);
+        answer.add_cost(0.25, true);
+        let messages = Arc::new(Mutex::new(MessageTree::from(vec![
+            Message::new(Role::user, "Hi".into()),
+            answer,
+        ])));
+
+        Conversation::fail_response(&messages, &EventEmitter::detached(0), ApiError::NetworkDown)
+            .await;
+
+        let messages = messages.lock().await;
+        assert_eq!(messages.len(), 2);
+        let error = messages.last().unwrap();
+        assert_eq!(*error.get_role(), Role::error);
+        assert_eq!(error.get_cost(), Some(0.25));
+        assert!(error.is_cost_estimated());
+        assert_eq!(error.get_attempt_costs(), &vec![0.5]);
+    }
+
+    #[test]
+    fn loads_messages_stored_as_a_list() {
+        let stored = r#"{
+            "name": "Greeting",
+            "id": 1,
+            "date_created": 0,
+            "messages": [
+                {"role": "user", "content": "Hi", "cost_dollars": null, "system_fingerprint": null},
+                {"role": "assistant", "content": "Hello", "cost_dollars": 0.001, "system_fingerprint": null}
+            ]
+        }"#;
+        let conversation: SerializedConversation = serde_json::from_str(stored).unwrap();
+
+        let contents: Vec<&str> = conversation
+            .messages
+            .iter()
+            .map(|message| message.get_content())
+            .collect();
+        assert_eq!(contents, ["Hi", "Hello"]);
+        assert_eq!(conversation.messages.iter_all().count(), 2);
+    }
+
+    #[test]
+    fn loads_messages_stored_as_a_tree() {
+        let mut tree: MessageTree = vec![
+            Message::new(Role::user, "Hi".to_string()),
+            Message::new(Role::assistant, "Hello".to_string()),
+        ]
+        .into();
+        tree.split_off(1);
+        tree.push(Message::new(Role::assistant, "Hey".to_string()));
+        let stored = serde_json::json!({
+            "name": "Greeting",
+            "id": 1,
+            "date_created": 0,
+            "messages": tree,
+        });
+
+        let conversation: SerializedConversation = serde_json::from_value(stored).unwrap();
+        assert_eq!(conversation.messages.iter_all().count(), 3);
+        assert_eq!(conversation.messages.last().unwrap().get_content(), "Hey");
+    }
+
+    /// A prompt that the model answered by calling the calculator, and the final answer.
+    async fn conversation_with_tool_call() -> Conversation {
+        let mut call = Message::new(Role::assistant, "".to_string());
+        call.set_tool_calls(vec![ToolCall {
+            id: "call_a".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: "calculator".to_string(),
+                arguments: r#"{"expression":"1+1"}"#.to_string(),
+            },
+        }]);
+
+        let conversation = Conversation::new();
+        *conversation.get_messages().lock().await = vec![
+            Message::new(Role::user, "What is 1+1?".to_string()),
+            call,
+            Message::tool_result("call_a".to_string(), "2".to_string()),
+            Message::new(Role::assistant, "2".to_string()),
+        ]
+        .into();
+        conversation
+    }
+
+    #[tokio::test]
+    async fn deleting_a_tool_result_deletes_its_call() {
+        let conversation = conversation_with_tool_call().await;
+        conversation.delete_message(2).await.unwrap();
+
+        let messages = conversation.get_messages().lock().await;
+        let deleted: Vec<bool> = messages.iter().map(Message::is_deleted).collect();
+        assert_eq!(deleted, [false, true, true, false]);
+    }
+
+    #[tokio::test]
+    async fn stats_leave_out_deleted_messages() {
+        let conversation = conversation_with_tool_call().await;
+        conversation.delete_message(1).await.unwrap();
+
+        let stats = conversation
+            .get_stats(&Model::unknown("gpt-4o", ProviderKind::OpenAi))
+            .await;
+        assert_eq!(stats.message_count, 2);
+    }
 }
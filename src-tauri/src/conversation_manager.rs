@@ -0,0 +1,162 @@
+use crate::conversation::Conversation;
+use anyhow::Result;
+use std::collections::HashMap;
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+#[derive(Error, Debug)]
+pub enum ManagerError {
+    #[error("Conversation {0} is not open")]
+    NotOpen(u32),
+}
+
+/// Keeps track of the conversations that are open, by id. Every conversation has its own lock
+/// and cancel token, so any number of them can stream at the same time.
+pub struct ConversationManager {
+    conversations: Mutex<HashMap<u32, Conversation>>,
+}
+
+impl ConversationManager {
+    /// Creates a manager with a single empty conversation, so there is always something to show.
+    pub fn new() -> Self {
+        let conversation = Conversation::new();
+        Self {
+            conversations: Mutex::new(HashMap::from([(conversation.get_id(), conversation)])),
+        }
+    }
+
+    /// Opens a new, empty conversation and returns its id.
+    pub async fn create(&self) -> u32 {
+        let conversation = Conversation::new();
+        let id = conversation.get_id();
+        self.conversations.lock().await.insert(id, conversation);
+        id
+    }
+
+    /// Returns the open conversation with `id`. Conversations share their state between clones,
+    /// so changes to the returned conversation are seen by everyone.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is not open.
+    pub async fn get(&self, id: u32) -> Result<Conversation, ManagerError> {
+        self.conversations
+            .lock()
+            .await
+            .get(&id)
+            .cloned()
+            .ok_or(ManagerError::NotOpen(id))
+    }
+
+    /// Opens the saved conversation with `id`, or returns it if it is open already.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation cannot be loaded.
+    pub async fn open(&self, id: u32) -> Result<Conversation> {
+        if let Ok(conversation) = self.get(id).await {
+            return Ok(conversation);
+        }
+
+        let conversation = Conversation::new();
+        conversation.load(id.into()).await?;
+        self.conversations
+            .lock()
+            .await
+            .insert(conversation.get_id(), conversation.clone());
+        Ok(conversation)
+    }
+
+    /// Closes the conversation with `id`, the response it is streaming is cancelled.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the conversation is not open.
+    pub async fn close(&self, id: u32) -> Result<(), ManagerError> {
+        let conversation = self
+            .conversations
+            .lock()
+            .await
+            .remove(&id)
+            .ok_or(ManagerError::NotOpen(id))?;
+        conversation.cancel();
+        Ok(())
+    }
+
+    /// Returns the ids of the open conversations, oldest first.
+    pub async fn list_open(&self) -> Vec<u32> {
+        let conversations = self.conversations.lock().await;
+        let mut conversations: Vec<&Conversation> = conversations.values().collect();
+        conversations.sort_by_key(|conversation| conversation.get_date_created());
+        conversations
+            .into_iter()
+            .map(|conversation| conversation.get_id())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::fs;
+
+    #[tokio::test]
+    async fn opens_lists_and_closes_conversations() {
+        let manager = ConversationManager::new();
+        let first = manager.list_open().await[0];
+        let second = manager.create().await;
+
+        assert_eq!(manager.list_open().await.len(), 2);
+        assert_eq!(manager.get(second).await.unwrap().get_id(), second);
+
+        manager.close(first).await.unwrap();
+        assert_eq!(manager.list_open().await, vec![second]);
+        assert!(matches!(
+            manager.get(first).await,
+            Err(ManagerError::NotOpen(id)) if id == first
+        ));
+        assert!(manager.close(first).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn every_conversation_has_its_own_lock() {
+        let manager = ConversationManager::new();
+        let first = manager.get(manager.list_open().await[0]).await.unwrap();
+        let second = manager.get(manager.create().await).await.unwrap();
+
+        let lock = first.lock_for_response().unwrap();
+        assert!(first.lock_for_response().is_err());
+        assert!(second.lock_for_response().is_ok());
+
+        drop(lock);
+        assert!(first.lock_for_response().is_ok());
+    }
+
+    #[tokio::test]
+    async fn opened_conversations_keep_their_creation_date() {
+        let data_dir = std::env::temp_dir().join("chatgptauri-manager-test");
+        std::env::set_var("XDG_DATA_HOME", &data_dir);
+
+        let mut path = Conversation::get_save_dir().await.unwrap();
+        path.push("42");
+        let saved = json!({
+            "name": "Saved",
+            "id": 42,
+            "date_created": 1,
+            "messages": [{"role": "user", "content": "Hi"}],
+        });
+        fs::write(&path, saved.to_string()).await.unwrap();
+
+        let manager = ConversationManager::new();
+        let created = manager.list_open().await[0];
+        let opened = manager.open(42).await.unwrap();
+
+        assert_eq!(opened.get_date_created(), 1);
+        assert_eq!(manager.list_open().await, vec![42, created]);
+        // Opening it again returns the conversation that is open already
+        assert_eq!(manager.open(42).await.unwrap().get_date_created(), 1);
+
+        fs::remove_dir_all(data_dir).await.unwrap();
+    }
+}
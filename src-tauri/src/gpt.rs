@@ -1,16 +1,37 @@
-use futures_core::Stream;
-use reqwest_eventsource::{self as reqwest_es, CannotCloneRequestError, EventSource};
+use crate::attachment::Attachment;
+use crate::providers::{ChatProvider, DeltaStream, ProviderError};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::pin::Pin;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tokio_stream::StreamExt;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum Role {
     user,
     system,
     assistant,
+    tool,
+    /// Replaces `system` in requests to reasoning models, which don't accept system messages
+    developer,
+    /// Errors are shown in the conversation, but never sent to the model
+    error,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::user => "user",
+            Self::system => "system",
+            Self::assistant => "assistant",
+            Self::tool => "tool",
+            Self::developer => "developer",
+            Self::error => "error",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -18,20 +39,291 @@ pub struct Message {
     role: Role,
     content: String,
     cost_dollars: Option<f32>,
+    /// Whether `cost_dollars` is based on our own token estimate, because the provider didn't
+    /// report its usage.
+    #[serde(default)]
+    cost_estimated: bool,
+    /// Identifies the backend configuration that generated this message, together with the seed
+    /// it can be used to reproduce a response.
+    system_fingerprint: Option<String>,
+    /// The tools the assistant asked to call in this message
+    tool_calls: Option<Vec<ToolCall>>,
+    /// The call that a `Role::tool` message contains the result of
+    tool_call_id: Option<String>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    /// Every choice that was generated when multiple were requested. `content` is a copy of the
+    /// selected one, so that is what the model sees when the conversation continues.
+    #[serde(default)]
+    candidates: Vec<String>,
+    #[serde(default)]
+    selected_candidate: usize,
+    /// Why the model stopped generating this message, `None` while it is still being generated
+    /// or if the provider didn't say.
+    #[serde(default)]
+    finish_reason: Option<FinishReason>,
+    /// Log probability of every token in `content`, when they were requested.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    logprobs: Vec<TokenLogprob>,
+    /// Tokens a reasoning model spent thinking, they are included in the cost but not shown.
+    #[serde(default)]
+    reasoning_tokens: usize,
+    /// Log probabilities of every candidate, `logprobs` is a copy of the selected one's.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    candidate_logprobs: Vec<Vec<TokenLogprob>>,
+    /// What the earlier attempts at this answer cost, oldest first. They were replaced when the
+    /// answer was regenerated, but they were paid for.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attempt_costs: Vec<f32>,
+    /// Shown, but never sent to the model
+    #[serde(default)]
+    excluded: bool,
+    /// Never dropped to make the conversation fit in the context window
+    #[serde(default)]
+    pinned: bool,
+    /// Neither shown nor sent to the model, the message is only kept so the indices of the
+    /// other messages don't change.
+    #[serde(default)]
+    deleted: bool,
+}
+
+/// A generated token with its log probability and, if requested, the most likely alternatives.
+/// This is the format of the OpenAI API.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+}
+
+/// Why the model stopped generating.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model finished its answer or hit a stop sequence
+    Stop,
+    /// The answer was cut off because it reached the token limit
+    Length,
+    ToolCalls,
+    /// The answer was withheld or cut off by the provider's content filter
+    ContentFilter,
+    /// Anything else the provider reported
+    Other(String),
+}
+
+impl FinishReason {
+    /// Maps the finish reasons of every provider onto ours.
+    pub fn from_provider(reason: &str) -> Self {
+        match reason {
+            "stop" | "end_turn" | "stop_sequence" | "STOP" => Self::Stop,
+            "length" | "max_tokens" | "MAX_TOKENS" => Self::Length,
+            "tool_calls" | "function_call" | "tool_use" => Self::ToolCalls,
+            "content_filter" | "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" => {
+                Self::ContentFilter
+            }
+            other => Self::Other(other.to_string()),
+        }
+    }
 }
 
 // This is the type that will be sent to the API
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ApiMessage {
-    role: Role,
-    content: String,
+    pub role: Role,
+    pub content: ApiContent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// Message content is plain text, unless images are attached. Then it is a list of parts in the
+/// OpenAI format, images are embedded as base64 `data:` urls.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ApiContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+/// An image that was embedded in the message content.
+pub struct InlineImage<'a> {
+    pub mime_type: &'a str,
+    pub data: &'a str,
+}
+
+impl ApiContent {
+    /// Returns all text in this content, without the images.
+    pub fn get_text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            Self::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        }
+    }
+
+    /// Puts `text` in front of the content, as its own paragraph.
+    pub fn prepend_text(&mut self, text: &str) {
+        match self {
+            Self::Text(content) => *content = format!("{text}\n\n{content}"),
+            Self::Parts(parts) => parts.insert(
+                0,
+                ContentPart::Text {
+                    text: text.to_string(),
+                },
+            ),
+        }
+    }
+
+    /// Returns every image that is embedded as a base64 `data:` url, for providers that want the
+    /// mime type and data separately.
+    pub fn get_images(&self) -> Vec<InlineImage<'_>> {
+        let parts = match self {
+            Self::Text(_) => return vec![],
+            Self::Parts(parts) => parts,
+        };
+
+        parts
+            .iter()
+            .filter_map(|part| match part {
+                ContentPart::ImageUrl { image_url } => {
+                    let (mime_type, data) = image_url
+                        .url
+                        .strip_prefix("data:")?
+                        .split_once(";base64,")?;
+                    Some(InlineImage { mime_type, data })
+                }
+                ContentPart::Text { .. } => None,
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Text(text) => text.is_empty(),
+            Self::Parts(parts) => parts.is_empty(),
+        }
+    }
 }
 
 impl Into<ApiMessage> for Message {
     fn into(self) -> ApiMessage {
+        let images: Vec<String> = self
+            .attachments
+            .iter()
+            .filter_map(|attachment| attachment.get_data_url())
+            .collect();
+
+        let content = match images.is_empty() {
+            true => ApiContent::Text(self.content),
+            false => {
+                let mut parts = vec![ContentPart::Text { text: self.content }];
+                parts.extend(images.into_iter().map(|url| ContentPart::ImageUrl {
+                    image_url: ImageUrl { url },
+                }));
+                ApiContent::Parts(parts)
+            }
+        };
+
         ApiMessage {
             role: self.role,
-            content: self.content,
+            content,
+            tool_calls: self.tool_calls,
+            tool_call_id: self.tool_call_id,
+        }
+    }
+}
+
+/// A function call requested by the model, in the OpenAI format.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments, as generated by the model. This is not guaranteed to be valid JSON.
+    pub arguments: String,
+}
+
+/// Describes a tool to the model, in the OpenAI format.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolDefinition {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments
+    pub parameters: serde_json::Value,
+}
+
+/// A fragment of a tool call. The id and name are sent once, the arguments are streamed in
+/// pieces that have to be concatenated. `index` identifies which call the fragment belongs to.
+#[derive(Serialize, Debug, Clone)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+impl ToolCallDelta {
+    /// Applies this fragment to the list of calls that is being reassembled.
+    pub fn apply(self, tool_calls: &mut Vec<ToolCall>) {
+        while tool_calls.len() <= self.index {
+            tool_calls.push(ToolCall {
+                id: String::new(),
+                kind: "function".to_string(),
+                function: FunctionCall {
+                    name: String::new(),
+                    arguments: String::new(),
+                },
+            });
+        }
+
+        let tool_call = &mut tool_calls[self.index];
+        if let Some(id) = self.id {
+            tool_call.id = id;
+        }
+        if let Some(name) = self.name {
+            tool_call.function.name.push_str(&name);
+        }
+        if let Some(arguments) = self.arguments {
+            tool_call.function.arguments.push_str(&arguments);
         }
     }
 }
@@ -48,11 +340,176 @@ impl Message {
             role,
             content,
             cost_dollars: None,
+            cost_estimated: false,
+            system_fingerprint: None,
+            tool_calls: None,
+            tool_call_id: None,
+            attachments: vec![],
+            candidates: vec![],
+            selected_candidate: 0,
+            finish_reason: None,
+            reasoning_tokens: 0,
+            logprobs: vec![],
+            candidate_logprobs: vec![],
+            attempt_costs: vec![],
+            excluded: false,
+            pinned: false,
+            deleted: false,
+        }
+    }
+
+    /// Prepares an empty message for `candidate_count` alternative choices.
+    pub fn with_candidate_count(mut self, candidate_count: usize) -> Self {
+        if candidate_count > 1 {
+            self.candidates = vec![String::new(); candidate_count];
+            self.candidate_logprobs = vec![vec![]; candidate_count];
+        }
+        self
+    }
+
+    /// Adds content to the choice with `index`. Messages without candidates only have a single
+    /// choice.
+    pub fn add_candidate_content(&mut self, index: usize, content: &str) {
+        if let Some(candidate) = self.candidates.get_mut(index) {
+            candidate.push_str(content);
+        }
+        if index == self.selected_candidate {
+            self.add_content(content);
+        }
+    }
+
+    /// Adds token log probabilities to the choice with `index`.
+    pub fn add_candidate_logprobs(&mut self, index: usize, logprobs: Vec<TokenLogprob>) {
+        if let Some(candidate_logprobs) = self.candidate_logprobs.get_mut(index) {
+            candidate_logprobs.extend(logprobs.iter().cloned());
         }
+        if index == self.selected_candidate {
+            self.logprobs.extend(logprobs);
+        }
+    }
+
+    pub fn get_logprobs(&self) -> &Vec<TokenLogprob> {
+        &self.logprobs
+    }
+
+    pub fn get_selected_candidate(&self) -> usize {
+        self.selected_candidate
+    }
+
+    pub fn get_candidates(&self) -> &Vec<String> {
+        &self.candidates
+    }
+
+    /// Makes the candidate with `index` the content of this message. Does nothing if there is no
+    /// such candidate.
+    pub fn select_candidate(&mut self, index: usize) {
+        if let Some(candidate) = self.candidates.get(index) {
+            self.content = candidate.clone();
+            self.logprobs = self
+                .candidate_logprobs
+                .get(index)
+                .cloned()
+                .unwrap_or_default();
+            self.selected_candidate = index;
+        }
+    }
+
+    /// Records the costs of the attempts at this answer that came before it.
+    pub fn with_attempt_costs(mut self, attempt_costs: Vec<f32>) -> Self {
+        self.attempt_costs = attempt_costs;
+        self
+    }
+
+    pub fn get_attempt_costs(&self) -> &Vec<f32> {
+        &self.attempt_costs
+    }
+
+    pub fn with_attachments(mut self, attachments: Vec<Attachment>) -> Self {
+        self.attachments = attachments;
+        self
+    }
+
+    pub fn get_attachments(&self) -> &Vec<Attachment> {
+        &self.attachments
+    }
+
+    pub fn get_attachments_mut(&mut self) -> &mut Vec<Attachment> {
+        &mut self.attachments
+    }
+
+    /// Creates a message that contains the result of a tool call.
+    pub fn tool_result(tool_call_id: String, content: String) -> Self {
+        let mut message = Self::new(Role::tool, content);
+        message.tool_call_id = Some(tool_call_id);
+        message
+    }
+
+    pub fn set_tool_calls(&mut self, tool_calls: Vec<ToolCall>) {
+        self.tool_calls = Some(tool_calls);
+    }
+
+    pub fn get_tool_calls(&self) -> Option<&Vec<ToolCall>> {
+        self.tool_calls.as_ref()
     }
 
-    pub fn set_cost(&mut self, cost: f32) {
-        self.cost_dollars = Some(cost);
+    /// Adds `cost` to the cost of this message, a continued message is paid for in multiple
+    /// requests. The total is estimated as soon as one of the parts is.
+    pub fn add_cost(&mut self, cost: f32, estimated: bool) {
+        self.cost_dollars = Some(self.cost_dollars.unwrap_or(0.0) + cost);
+        self.cost_estimated |= estimated;
+    }
+
+    pub fn get_cost(&self) -> Option<f32> {
+        self.cost_dollars
+    }
+
+    pub fn is_cost_estimated(&self) -> bool {
+        self.cost_estimated
+    }
+
+    pub fn add_reasoning_tokens(&mut self, reasoning_tokens: usize) {
+        self.reasoning_tokens += reasoning_tokens;
+    }
+
+    pub fn get_reasoning_tokens(&self) -> usize {
+        self.reasoning_tokens
+    }
+
+    pub fn set_finish_reason(&mut self, finish_reason: Option<FinishReason>) {
+        self.finish_reason = finish_reason;
+    }
+
+    pub fn set_excluded(&mut self, excluded: bool) {
+        self.excluded = excluded;
+    }
+
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.pinned = pinned;
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    pub fn delete(&mut self) {
+        self.deleted = true;
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+
+    /// Whether this message is sent to the model. Errors are only there for the user.
+    pub fn is_sent(&self) -> bool {
+        self.role != Role::error && !self.excluded && !self.deleted
+    }
+
+    pub fn get_role(&self) -> &Role {
+        &self.role
+    }
+
+    pub fn set_system_fingerprint(&mut self, system_fingerprint: String) {
+        self.system_fingerprint = Some(system_fingerprint);
     }
 
     pub fn get_content(&self) -> &str {
@@ -60,123 +517,841 @@ impl Message {
     }
 }
 
+/// Everything needed to reach a provider's API.
+#[derive(Clone, Debug)]
+pub struct ApiConfig {
+    pub api_key: Option<String>,
+    pub base_url: String,
+    pub headers: HashMap<String, String>,
+    /// The shared client, so connections are reused and the network settings apply
+    pub client: reqwest::Client,
+}
+
+impl ApiConfig {
+    pub fn get_url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), path)
+    }
+}
+
+/// Parameters that control how the model samples its response. Anything that is `None` is left
+/// up to the provider's default.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SamplingParameters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+}
+
+impl SamplingParameters {
+    /// Returns a copy of `self` where every parameter that is set in `overrides` is replaced.
+    pub fn merge(&self, overrides: &SamplingParameters) -> SamplingParameters {
+        SamplingParameters {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            max_tokens: overrides.max_tokens.or(self.max_tokens),
+            presence_penalty: overrides.presence_penalty.or(self.presence_penalty),
+            frequency_penalty: overrides.frequency_penalty.or(self.frequency_penalty),
+            stop: match overrides.stop.is_empty() {
+                true => self.stop.clone(),
+                false => overrides.stop.clone(),
+            },
+            seed: overrides.seed.or(self.seed),
+        }
+    }
+}
+
+/// How many reasoning tokens a reasoning model spends before it answers.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+/// Forces the model to answer with JSON, optionally matching a schema.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    pub schema: serde_json::Value,
+    #[serde(default)]
+    pub strict: bool,
+}
+
+impl ResponseFormat {
+    /// Checks whether `content` is valid JSON and matches the schema, if there is one. Returns a
+    /// description of every problem that was found, an empty list means the content is valid.
+    pub fn validate(&self, content: &str) -> Vec<String> {
+        let instance: serde_json::Value = match serde_json::from_str(content) {
+            Ok(instance) => instance,
+            Err(e) => return vec![format!("Response is not valid JSON: {}", e)],
+        };
+
+        let schema = match self {
+            Self::JsonObject => return vec![],
+            Self::JsonSchema { json_schema } => &json_schema.schema,
+        };
+
+        let compiled_schema = match jsonschema::JSONSchema::compile(schema) {
+            Ok(compiled_schema) => compiled_schema,
+            Err(e) => return vec![format!("Schema is invalid: {}", e)],
+        };
+
+        // Bound to a variable because the error iterator borrows the schema and the instance
+        let errors: Vec<String> = match compiled_schema.validate(&instance) {
+            Ok(()) => vec![],
+            Err(errors) => errors
+                .map(|error| format!("{}: {}", error.instance_path, error))
+                .collect(),
+        };
+        errors
+    }
+}
+
+/// Sent before every conversation, so the model knows how math is rendered.
+pub const SYSTEM_PROMPT: &str = "You are about to enter a conversation with a user, they may or may not ask you questions about math. If you are trying to express a formula or variable or any other math concept that can be expressed in LaTeX, please do so. You can create an inline LaTeX block with a single dollar sign, for example: $a$. If you want to create a block that is centered, please use double dollar signs: $$a$$. If your output happens to contain a dollar sign, but you do not want the dollar sign to be interpreted as the start of a LaTeX block, please escape it using a backslash like this: \\$";
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Request {
     pub model: String,
     pub messages: Vec<ApiMessage>,
     pub stream: bool,
+    #[serde(flatten)]
+    pub parameters: SamplingParameters,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    /// Number of alternative choices to generate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<usize>,
+    /// Whether the log probability of every generated token is returned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    /// Number of most likely alternatives that are returned for every token, 0 to 20
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+    /// Replaces `max_tokens` for reasoning models, it includes the reasoning tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StreamOptions {
+    /// Makes the API send the token usage in a final chunk that has no choices
+    pub include_usage: bool,
 }
 
 impl Request {
     pub fn new(messages: Vec<Message>, model: &str) -> Self {
         let mut messages = messages;
-        messages.insert(0, Message::new(Role::system, "You are about to enter a conversation with a user, they may or may not ask you questions about math. If you are trying to express a formula or variable or any other math concept that can be expressed in LaTeX, please do so. You can create an inline LaTeX block with a single dollar sign, for example: $a$. If you want to create a block that is centered, please use double dollar signs: $$a$$. If your output happens to contain a dollar sign, but you do not want the dollar sign to be interpreted as the start of a LaTeX block, please escape it using a backslash like this: \\$".to_string()));
+        messages.insert(0, Message::new(Role::system, SYSTEM_PROMPT.to_string()));
 
         Self {
             model: model.to_string(),
-            messages: messages.into_iter().map(|message| message.into()).collect(),
+            messages: messages
+                .into_iter()
+                .filter(|message| message.is_sent())
+                .map(|message| message.into())
+                .collect(),
             stream: true,
+            parameters: SamplingParameters::default(),
+            tools: vec![],
+            response_format: None,
+            n: None,
+            logprobs: None,
+            top_logprobs: None,
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
+            max_completion_tokens: None,
+            reasoning_effort: None,
         }
     }
 
-    pub fn do_request(
-        self,
-        api_key: &str,
-    ) -> Result<
-        Pin<Box<impl Stream<Item = Result<MessageDelta, StreamError>>>>,
-        CannotCloneRequestError,
-    > {
-        let client = reqwest::Client::new();
-
-        dbg!(&self);
-
-        let source = EventSource::new(
-            client
-                .post("https://api.openai.com/v1/chat/completions")
-                .header("Authorization", format!("Bearer {}", api_key))
-                .json(&self),
-        )?;
-
-        Ok(Box::pin(source.then(Request::handle_eventsource_event)))
-    }
-
-    async fn handle_eventsource_event(
-        event: Result<reqwest_eventsource::Event, reqwest_eventsource::Error>,
-    ) -> Result<MessageDelta, StreamError> {
-        match event {
-            Ok(event) => match event {
-                reqwest_es::Event::Open => Ok(MessageDelta::NoData),
-                reqwest_es::Event::Message(event) => Self::process_event(event),
-            },
-            Err(e) => Err(e.into()),
+    pub fn with_choice_count(mut self, choice_count: usize) -> Self {
+        self.n = match choice_count {
+            0 | 1 => None,
+            choice_count => Some(choice_count),
+        };
+        self
+    }
+
+    /// Requests the log probability of every token, with the `top_logprobs` most likely
+    /// alternatives. `None` doesn't request them.
+    pub fn with_logprobs(mut self, top_logprobs: Option<u8>) -> Self {
+        self.logprobs = top_logprobs.map(|_| true);
+        self.top_logprobs = top_logprobs.filter(|top_logprobs| *top_logprobs > 0);
+        self
+    }
+
+    pub fn with_response_format(mut self, response_format: Option<ResponseFormat>) -> Self {
+        self.response_format = response_format;
+        self
+    }
+
+    pub fn with_tools(mut self, tools: Vec<ToolDefinition>) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    pub fn with_parameters(mut self, parameters: SamplingParameters) -> Self {
+        self.parameters = parameters;
+        self
+    }
+
+    /// Shapes the request for a reasoning model. These models reject the system role, sampling
+    /// parameters, log probabilities and `max_tokens`, so the system prompt becomes a developer
+    /// message and the token limit moves to `max_completion_tokens`.
+    pub fn for_reasoning_model(mut self, reasoning_effort: ReasoningEffort) -> Self {
+        for message in &mut self.messages {
+            if message.role == Role::system {
+                message.role = Role::developer;
+            }
         }
+
+        self.max_completion_tokens = self.parameters.max_tokens.take();
+        self.parameters.temperature = None;
+        self.parameters.top_p = None;
+        self.parameters.presence_penalty = None;
+        self.parameters.frequency_penalty = None;
+        self.logprobs = None;
+        self.top_logprobs = None;
+        self.reasoning_effort = Some(reasoning_effort);
+        self
     }
 
-    fn process_event(event: eventsource_stream::Event) -> Result<MessageDelta, StreamError> {
-        let data = event.data;
+    /// Shapes the request for a model that accepts neither system nor developer messages, like
+    /// o1-mini. Those messages are folded into the first prompt instead, or become the first
+    /// prompt if there is none.
+    pub fn without_system_messages(mut self) -> Self {
+        let (instructions, messages): (Vec<ApiMessage>, Vec<ApiMessage>) = self
+            .messages
+            .into_iter()
+            .partition(|message| matches!(message.role, Role::system | Role::developer));
+        self.messages = messages;
+        if instructions.is_empty() {
+            return self;
+        }
 
-        if data == "[DONE]" {
-            return Ok(MessageDelta::Done);
+        let instructions = instructions
+            .iter()
+            .map(|message| message.content.get_text())
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        match self
+            .messages
+            .iter_mut()
+            .find(|message| message.role == Role::user)
+        {
+            Some(prompt) => prompt.content.prepend_text(&instructions),
+            None => self.messages.insert(
+                0,
+                ApiMessage {
+                    role: Role::user,
+                    content: ApiContent::Text(instructions),
+                    tool_calls: None,
+                    tool_call_id: None,
+                },
+            ),
         }
+        self
+    }
 
-        // Parse data
-        let data: openai_types::EventData = serde_json::from_str(&data)?;
+    /// Sends this request to `provider` and returns a stream of the deltas it responds with.
+    /// Rate limits and transient errors are retried according to `retry_policy`, but only until
+    /// the first content has arrived, so content is never duplicated. A `MessageDelta::Retrying`
+    /// is sent before every retry.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the provider fails to start the request.
+    pub fn do_request(
+        self,
+        provider: Arc<dyn ChatProvider>,
+        retry_policy: &RetryPolicy,
+    ) -> Result<DeltaStream, ProviderError> {
+        let mut delta_stream = provider.stream_chat(self.clone())?;
+        let retry_policy = retry_policy.clone();
 
-        if data.choices.len() == 0 {
-            return Err(StreamError::InvalidEvent);
+        Ok(Box::pin(async_stream::stream! {
+            let mut attempt = 0;
+            let mut received_content = false;
+            while let Some(delta) = delta_stream.next().await {
+                let error = match delta {
+                    Ok(delta) => {
+                        if let MessageDelta::Delta(..) | MessageDelta::ToolCall(_) = delta {
+                            received_content = true;
+                        }
+                        yield Ok(delta);
+                        continue;
+                    }
+                    Err(error) => error,
+                };
+
+                let retry_after = error.get_retry_after();
+                let error = ApiError::from(error);
+                if received_content
+                    || !error.is_transient()
+                    || attempt >= retry_policy.max_retries
+                {
+                    yield Err(error.into());
+                    return;
+                }
+
+                attempt += 1;
+                let delay = retry_policy.get_delay(attempt, retry_after);
+                yield Ok(MessageDelta::Retrying {
+                    attempt,
+                    delay_ms: delay.as_millis() as u64,
+                });
+                tokio::time::sleep(delay).await;
+
+                delta_stream = match provider.stream_chat(self.clone()) {
+                    Ok(delta_stream) => delta_stream,
+                    Err(e) => {
+                        yield Err(ApiError::Other(e.to_string()).into());
+                        return;
+                    }
+                };
+            }
+        }))
+    }
+}
+
+/// How requests that failed because of a rate limit or a transient error are retried.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay before the first retry, it doubles with every attempt
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_delay_ms: 1000,
+            max_delay_ms: 60_000,
         }
+    }
+}
 
-        match &data.choices[0].delta {
-            openai_types::Delta::Role { role } => Ok(MessageDelta::Role(role.clone())),
-            openai_types::Delta::Content { content } => Ok(MessageDelta::Delta(content.into())),
-            openai_types::Delta::NoData {} => Ok(MessageDelta::NoData),
+impl RetryPolicy {
+    /// Returns how long to wait before retry number `attempt`, counting from 1. The delay the
+    /// server asked for is used when there is one, otherwise the delay grows exponentially up to
+    /// `max_delay_ms`. The server's delay isn't capped, retrying sooner would only fail again. Up
+    /// to 25% jitter is added so clients that failed together don't retry together.
+    pub fn get_delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let jitter = rand::thread_rng().gen_range(0.0..=0.25);
+        match retry_after {
+            Some(retry_after) => retry_after + retry_after.mul_f64(jitter),
+            None => {
+                let backoff = Duration::from_millis(
+                    self.initial_delay_ms
+                        .saturating_mul(1 << attempt.saturating_sub(1).min(16)),
+                );
+                (backoff + backoff.mul_f64(jitter)).min(Duration::from_millis(self.max_delay_ms))
+            }
         }
     }
 }
 
+/// Reads how long the server wants us to wait before retrying from the `Retry-After` header, or
+/// if it didn't send one, from the `x-ratelimit-reset-*` header of the limit that was hit.
+pub fn get_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let milliseconds = header("retry-after-ms").and_then(|value| value.parse::<f64>().ok());
+    if let Some(milliseconds) = milliseconds {
+        return Some(Duration::from_secs_f64(milliseconds / 1000.0));
+    }
+    // Retry-After can also be a date, but nobody seems to send that
+    if let Some(seconds) = header("retry-after").and_then(|value| value.parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(seconds));
+    }
+
+    ["requests", "tokens"]
+        .iter()
+        .filter(|limit| header(&format!("x-ratelimit-remaining-{}", limit)) == Some("0"))
+        .filter_map(|limit| header(&format!("x-ratelimit-reset-{}", limit)))
+        .filter_map(parse_reset_duration)
+        .max()
+}
+
+/// Parses durations like `20ms`, `1.5s` or `6m0s`, which is how OpenAI formats the rate limit
+/// reset headers.
+fn parse_reset_duration(value: &str) -> Option<Duration> {
+    let mut seconds = 0.0;
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let number: f64 = rest[..number_end].parse().ok()?;
+        rest = &rest[number_end..];
+
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        seconds += number
+            * match &rest[..unit_end] {
+                "h" => 3600.0,
+                "m" => 60.0,
+                "s" => 1.0,
+                "ms" => 0.001,
+                _ => return None,
+            };
+        rest = &rest[unit_end..];
+    }
+
+    Some(Duration::from_secs_f64(seconds))
+}
+
 #[derive(Debug, Error)]
 pub enum StreamError {
     #[error("Error while reading response stream")]
     StreamReadFailed(#[from] reqwest_eventsource::Error),
 
-    #[error("Invalid response from openai api")]
+    #[error("Error while making the request")]
+    RequestFailed(#[from] reqwest::Error),
+
+    #[error("Invalid response from the api")]
     InvalidJson(#[from] serde_json::Error),
 
-    #[error("Invalid response from openai api")]
-    InvalidEvent,
+    #[error("The api returned an error: {0}")]
+    ApiError(#[from] ApiError),
+
+    /// The server responded with an error status, `retry_after` is how long it asked us to wait
+    /// before trying again.
+    #[error("The api returned an error: {error}")]
+    ErrorResponse {
+        error: ApiError,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl StreamError {
+    pub fn get_retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::ErrorResponse { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// An error that is worth telling the user about, because they can do something about it.
+#[derive(Debug, Clone, Serialize, Error)]
+#[serde(tag = "kind", content = "details", rename_all = "snake_case")]
+pub enum ApiError {
+    #[error("The API key was rejected, please check it in the settings menu")]
+    InvalidKey,
+
+    #[error("You have run out of credits, please check your plan and billing details")]
+    QuotaExhausted,
+
+    #[error("You are being rate limited, please wait a moment before trying again")]
+    RateLimited,
+
+    #[error("The conversation is too long for the model, remove some messages or pick a model with a larger context window")]
+    ContextTooLong,
+
+    #[error("The model is not available ({0}), please pick another one in the settings menu")]
+    ModelNotFound(String),
+
+    #[error("The API had an internal error ({0}), please try again later")]
+    ServerError(u16),
+
+    #[error("Could not reach the API, please check your internet connection")]
+    NetworkDown,
+
+    #[error("The API returned an error: {0}")]
+    Other(String),
 }
 
-mod openai_types {
-    use serde::Deserialize;
+impl ApiError {
+    /// Whether the request might succeed if it is sent again.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited | Self::ServerError(_) | Self::NetworkDown
+        )
+    }
+
+    /// Classifies an unsuccessful response by its status code and, if there is one, the error in
+    /// its body. OpenAI and most compatible servers respond with
+    /// `{"error": {"message": ..., "type": ..., "code": ...}}`.
+    pub fn from_response(status: reqwest::StatusCode, body: Option<&str>) -> Self {
+        let error = body
+            .and_then(|body| serde_json::from_str::<ErrorBody>(body).ok())
+            .map(|body| body.error);
+        let code = error
+            .as_ref()
+            .and_then(|error| error.code.clone().or(error.kind.clone()))
+            .unwrap_or_default();
+
+        let message = match error {
+            Some(error) => error.message,
+            None => format!("status code {}", status.as_u16()),
+        };
 
-    #[derive(Debug, Deserialize)]
-    pub struct EventData {
-        pub object: String,
-        pub created: isize,
-        pub model: String,
-        pub choices: Vec<Choice>,
+        match (status.as_u16(), code.as_str()) {
+            (_, "invalid_api_key") | (401, _) => Self::InvalidKey,
+            (_, "insufficient_quota") => Self::QuotaExhausted,
+            (_, "context_length_exceeded") => Self::ContextTooLong,
+            (_, "model_not_found") => Self::ModelNotFound(message),
+            (429, _) => Self::RateLimited,
+            (status, _) if status >= 500 => Self::ServerError(status),
+            _ => Self::Other(message),
+        }
     }
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorDetails,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetails {
+    message: String,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    code: Option<String>,
+}
 
-    #[derive(Debug, Deserialize)]
-    pub struct Choice {
-        pub finish_reason: Option<String>,
-        pub index: isize,
-        pub delta: Delta,
+impl From<reqwest::Error> for ApiError {
+    fn from(error: reqwest::Error) -> Self {
+        match error.status() {
+            Some(status) => Self::from_response(status, None),
+            None if error.is_connect() || error.is_timeout() => Self::NetworkDown,
+            None => Self::Other(error.to_string()),
+        }
     }
+}
 
-    #[derive(Debug, Deserialize)]
-    #[serde(untagged)]
-    pub enum Delta {
-        Role { role: super::Role },
-        Content { content: String },
-        NoData {},
+impl From<StreamError> for ApiError {
+    fn from(error: StreamError) -> Self {
+        match error {
+            StreamError::StreamReadFailed(reqwest_eventsource::Error::InvalidStatusCode(
+                status,
+            )) => Self::from_response(status, None),
+            StreamError::StreamReadFailed(reqwest_eventsource::Error::Transport(error)) => {
+                error.into()
+            }
+            StreamError::RequestFailed(error) => error.into(),
+            StreamError::ApiError(error) => error,
+            StreamError::ErrorResponse { error, .. } => error,
+            error => Self::Other(error.to_string()),
+        }
+    }
+}
+
+/// Token usage as reported by the provider. Providers may report the prompt and completion
+/// counts in separate events, so every field is optional.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Usage {
+    /// All input tokens, including the cached ones
+    pub prompt_tokens: Option<usize>,
+    /// The part of the input tokens that was read from the provider's prompt cache
+    pub cached_tokens: Option<usize>,
+    /// All output tokens, including the reasoning tokens
+    pub completion_tokens: Option<usize>,
+    /// The part of the output tokens the model spent on reasoning, they are billed but never
+    /// shown
+    pub reasoning_tokens: Option<usize>,
+}
+
+impl Usage {
+    /// Overwrites the counts in `self` with the ones that are present in `other`.
+    pub fn update(&mut self, other: Usage) {
+        if other.prompt_tokens.is_some() {
+            self.prompt_tokens = other.prompt_tokens;
+        }
+        if other.cached_tokens.is_some() {
+            self.cached_tokens = other.cached_tokens;
+        }
+        if other.completion_tokens.is_some() {
+            self.completion_tokens = other.completion_tokens;
+        }
+        if other.reasoning_tokens.is_some() {
+            self.reasoning_tokens = other.reasoning_tokens;
+        }
     }
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub enum MessageDelta {
-    Delta(String),
+    /// Content for the choice with the given index
+    Delta(usize, String),
     Role(Role),
+    Usage(Usage),
+    SystemFingerprint(String),
+    ToolCall(ToolCallDelta),
+    /// Why the choice with the given index ended
+    FinishReason(usize, FinishReason),
+    /// Log probabilities of the tokens that were just generated for the choice with the given
+    /// index
+    Logprobs(usize, Vec<TokenLogprob>),
+    /// The request failed and is sent again after `delay_ms`
+    Retrying {
+        attempt: u32,
+        delay_ms: u64,
+    },
     NoData,
     Done,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    fn tool_call_delta(
+        index: usize,
+        id: Option<&str>,
+        name: Option<&str>,
+        arguments: Option<&str>,
+    ) -> ToolCallDelta {
+        ToolCallDelta {
+            index,
+            id: id.map(str::to_string),
+            name: name.map(str::to_string),
+            arguments: arguments.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn reassembles_interleaved_tool_calls() {
+        let mut tool_calls: Vec<ToolCall> = vec![];
+        for delta in [
+            tool_call_delta(0, Some("call_a"), Some("calculator"), Some("")),
+            tool_call_delta(0, None, None, Some(r#"{"expre"#)),
+            tool_call_delta(1, Some("call_b"), Some("calculator"), None),
+            tool_call_delta(0, None, None, Some(r#"ssion":"1+1"}"#)),
+            tool_call_delta(1, None, None, Some(r#"{"expression":"2*3"}"#)),
+        ] {
+            delta.apply(&mut tool_calls);
+        }
+
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[0].id, "call_a");
+        assert_eq!(tool_calls[0].function.name, "calculator");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"expression":"1+1"}"#);
+        assert_eq!(tool_calls[1].id, "call_b");
+        assert_eq!(tool_calls[1].function.arguments, r#"{"expression":"2*3"}"#);
+    }
+
+    #[test]
+    fn fills_gaps_when_a_later_call_arrives_first() {
+        let mut tool_calls: Vec<ToolCall> = vec![];
+        tool_call_delta(1, Some("call_b"), Some("calculator"), None).apply(&mut tool_calls);
+
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[0].id, "");
+        assert_eq!(tool_calls[0].kind, "function");
+        assert_eq!(tool_calls[1].id, "call_b");
+    }
+
+    #[test]
+    fn classifies_error_responses() {
+        let not_found = r#"{"error":{"message":"The model `gpt-9` does not exist","type":"invalid_request_error","code":"model_not_found"}}"#;
+        let quota = r#"{"error":{"message":"You exceeded your current quota","type":"insufficient_quota","code":null}}"#;
+        let too_long = r#"{"error":{"message":"This model's maximum context length is 8192 tokens","type":"invalid_request_error","code":"context_length_exceeded"}}"#;
+
+        assert!(matches!(
+            ApiError::from_response(StatusCode::NOT_FOUND, Some(not_found)),
+            ApiError::ModelNotFound(message) if message.contains("gpt-9")
+        ));
+        assert!(matches!(
+            ApiError::from_response(StatusCode::TOO_MANY_REQUESTS, Some(quota)),
+            ApiError::QuotaExhausted
+        ));
+        assert!(matches!(
+            ApiError::from_response(StatusCode::BAD_REQUEST, Some(too_long)),
+            ApiError::ContextTooLong
+        ));
+        assert!(matches!(
+            ApiError::from_response(StatusCode::UNAUTHORIZED, None),
+            ApiError::InvalidKey
+        ));
+        assert!(matches!(
+            ApiError::from_response(StatusCode::TOO_MANY_REQUESTS, None),
+            ApiError::RateLimited
+        ));
+        assert!(matches!(
+            ApiError::from_response(StatusCode::BAD_GATEWAY, Some("<html>Bad gateway</html>")),
+            ApiError::ServerError(502)
+        ));
+        assert!(matches!(
+            ApiError::from_response(StatusCode::BAD_REQUEST, None),
+            ApiError::Other(message) if message == "status code 400"
+        ));
+    }
+
+    #[test]
+    fn folds_system_messages_into_the_first_prompt() {
+        let request = Request::new(
+            vec![
+                Message::new(
+                    Role::system,
+                    "Summary of the earlier conversation: hi".to_string(),
+                ),
+                Message::new(Role::user, "What is 1+1?".to_string()),
+            ],
+            "o1-mini",
+        )
+        .for_reasoning_model(ReasoningEffort::default())
+        .without_system_messages();
+
+        assert_eq!(request.messages.len(), 1);
+        assert_eq!(request.messages[0].role, Role::user);
+        assert_eq!(
+            request.messages[0].content.get_text(),
+            format!("{SYSTEM_PROMPT}\n\nSummary of the earlier conversation: hi\n\nWhat is 1+1?")
+        );
+    }
+
+    #[test]
+    fn parses_reset_durations() {
+        assert_eq!(
+            parse_reset_duration("20ms"),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            parse_reset_duration("1.5s"),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(
+            parse_reset_duration("1h2m3s"),
+            Some(Duration::from_secs(3723))
+        );
+        assert_eq!(parse_reset_duration("soon"), None);
+        assert_eq!(parse_reset_duration("5"), None);
+        assert_eq!(parse_reset_duration("5d"), None);
+    }
+
+    #[test]
+    fn reads_retry_after_headers() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = reqwest::header::HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, reqwest::header::HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        assert_eq!(get_retry_after(&headers(&[])), None);
+        assert_eq!(
+            get_retry_after(&headers(&[("retry-after-ms", "250"), ("retry-after", "1")])),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            get_retry_after(&headers(&[("retry-after", "2")])),
+            Some(Duration::from_secs(2))
+        );
+        // Only the reset of a limit that was hit counts, and the longest one wins
+        assert_eq!(
+            get_retry_after(&headers(&[
+                ("x-ratelimit-remaining-requests", "0"),
+                ("x-ratelimit-reset-requests", "1s"),
+                ("x-ratelimit-remaining-tokens", "0"),
+                ("x-ratelimit-reset-tokens", "6m0s"),
+            ])),
+            Some(Duration::from_secs(360))
+        );
+        assert_eq!(
+            get_retry_after(&headers(&[
+                ("x-ratelimit-remaining-requests", "10"),
+                ("x-ratelimit-reset-requests", "1s"),
+            ])),
+            None
+        );
+    }
+
+    #[test]
+    fn caps_only_the_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_delay_ms: 1000,
+            max_delay_ms: 5000,
+        };
+        let within = |delay: Duration, millis: u64| {
+            delay >= Duration::from_millis(millis) && delay <= Duration::from_millis(millis * 5 / 4)
+        };
+
+        assert!(within(policy.get_delay(1, None), 1000));
+        assert!(within(policy.get_delay(3, None), 4000));
+        assert_eq!(policy.get_delay(8, None), Duration::from_millis(5000));
+        assert!(within(
+            policy.get_delay(1, Some(Duration::from_secs(30))),
+            30_000
+        ));
+    }
+
+    fn schema_format() -> ResponseFormat {
+        ResponseFormat::JsonSchema {
+            json_schema: JsonSchemaFormat {
+                name: "answer".into(),
+                schema: serde_json::json!({
+                    "type": "object",
+                    "properties": {"answer": {"type": "integer"}},
+                    "required": ["answer"],
+                }),
+                strict: true,
+            },
+        }
+    }
+
+    #[test]
+    fn json_object_accepts_any_json() {
+        assert!(ResponseFormat::JsonObject
+            .validate(r#"{"answer": "yes"}"#)
+            .is_empty());
+        assert!(ResponseFormat::JsonObject.validate("[1, 2]").is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        for format in [ResponseFormat::JsonObject, schema_format()] {
+            let problems = format.validate("{\"answer\": ");
+            assert_eq!(problems.len(), 1);
+            assert!(problems[0].starts_with("Response is not valid JSON"));
+        }
+    }
+
+    #[test]
+    fn json_schema_reports_every_violation() {
+        assert!(schema_format().validate(r#"{"answer": 42}"#).is_empty());
+
+        let problems = schema_format().validate(r#"{"answer": "42"}"#);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("/answer: "));
+
+        assert_eq!(schema_format().validate("{}").len(), 1);
+    }
+}
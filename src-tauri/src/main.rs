@@ -1,17 +1,31 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
+use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
 
 use conversation::SerializedConversation;
 use serde::Serialize;
 use tauri::async_runtime::Mutex;
 
+mod attachment;
+mod context;
 mod conversation;
+mod conversation_manager;
 mod gpt;
+mod message_tree;
+mod models;
+mod providers;
 mod settings;
+mod tokenizer;
+mod tools;
 
-use crate::conversation::{Conversation, CancelState};
-use settings::Settings;
+use crate::conversation::{Conversation, ConversationStats, EventEmitter, TokenProbability};
+use crate::conversation_manager::ConversationManager;
+use gpt::{ResponseFormat, SamplingParameters};
+use message_tree::Branches;
+use models::{Model, ModelRegistry};
+use providers::ProviderKind;
+use settings::{HttpClient, Settings};
 
 #[derive(Clone, Debug, Serialize)]
 struct PromptResponse {
@@ -22,22 +36,39 @@ struct PromptResponse {
 #[tauri::command]
 async fn prompt(
     prompt: &str,
-    conversation: tauri::State<'_, Conversation>,
+    image_paths: Option<Vec<PathBuf>>,
+    choice_count: Option<usize>,
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
     settings: tauri::State<'_, Mutex<Settings>>,
-    cancel_state: tauri::State<'_, CancelState>,
+    models: tauri::State<'_, Mutex<ModelRegistry>>,
+    http_client: tauri::State<'_, HttpClient>,
     window: tauri::Window,
 ) -> Result<(), String> {
-    let cancel_state = cancel_state.inner().clone();
-    let settings = settings.lock().await;
-    let api_key = {
-        match settings.get_key().as_ref() {
-            Some(key) => key.clone(),
-            None => return Err("Please provide an API key in the settings menu".to_string()),
-        }
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
     };
 
-    let model = settings.get_model();
-    let prompt_result = conversation.prompt(prompt, &api_key, model.clone(), &window, cancel_state).await;
+    let settings = settings.lock().await.clone();
+    let model = settings.get_model(&*models.lock().await);
+    let client = http_client.get().await;
+    if let Err(e) = settings.get_provider(&client) {
+        return Err(e.to_string());
+    }
+
+    let image_paths = image_paths.unwrap_or_default();
+    let prompt_result = conversation
+        .prompt(
+            prompt,
+            &image_paths,
+            choice_count.unwrap_or(1),
+            &settings,
+            &model,
+            &client,
+            &window,
+        )
+        .await;
 
     if let Err(e) = prompt_result {
         return Err(e.to_string());
@@ -47,7 +78,110 @@ async fn prompt(
 }
 
 #[tauri::command]
-async fn clear_messages(conversation: tauri::State<'_, Conversation>) -> Result<(), String> {
+async fn continue_generation(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+    settings: tauri::State<'_, Mutex<Settings>>,
+    models: tauri::State<'_, Mutex<ModelRegistry>>,
+    http_client: tauri::State<'_, HttpClient>,
+    window: tauri::Window,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let settings = settings.lock().await.clone();
+    let model = settings.get_model(&*models.lock().await);
+    let client = http_client.get().await;
+    if let Err(e) = settings.get_provider(&client) {
+        return Err(e.to_string());
+    }
+
+    if let Err(e) = conversation
+        .continue_generation(&settings, &model, &client, &window)
+        .await
+    {
+        return Err(e.to_string());
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+async fn edit_message(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+    message_index: usize,
+    content: String,
+    settings: tauri::State<'_, Mutex<Settings>>,
+    models: tauri::State<'_, Mutex<ModelRegistry>>,
+    http_client: tauri::State<'_, HttpClient>,
+    window: tauri::Window,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let settings = settings.lock().await.clone();
+    let model = settings.get_model(&*models.lock().await);
+    let client = http_client.get().await;
+    if let Err(e) = settings.get_provider(&client) {
+        return Err(e.to_string());
+    }
+
+    if let Err(e) = conversation
+        .edit_message(message_index, content, &settings, &model, &client, &window)
+        .await
+    {
+        return Err(e.to_string());
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+async fn regenerate(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+    settings: tauri::State<'_, Mutex<Settings>>,
+    models: tauri::State<'_, Mutex<ModelRegistry>>,
+    http_client: tauri::State<'_, HttpClient>,
+    window: tauri::Window,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let settings = settings.lock().await.clone();
+    let model = settings.get_model(&*models.lock().await);
+    let client = http_client.get().await;
+    if let Err(e) = settings.get_provider(&client) {
+        return Err(e.to_string());
+    }
+
+    if let Err(e) = conversation
+        .regenerate(&settings, &model, &client, &window)
+        .await
+    {
+        return Err(e.to_string());
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+async fn clear_messages(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
     if let Err(e) = conversation.clear().await {
         return Err(e.to_string());
     }
@@ -57,19 +191,24 @@ async fn clear_messages(conversation: tauri::State<'_, Conversation>) -> Result<
 
 #[tauri::command]
 async fn save(
-    conversation: tauri::State<'_, Conversation>,
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
     settings: tauri::State<'_, Mutex<Settings>>,
+    http_client: tauri::State<'_, HttpClient>,
 ) -> Result<(), String> {
-    let settings = settings.lock().await;
-
-    let api_key = {
-        match settings.get_key().as_ref() {
-            Some(key) => key.clone(),
-            None => return Err("Please provide an API key in the settings menu".to_string()),
-        }
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
     };
 
-    if let Err(e) = conversation.save(&api_key).await {
+    let settings = settings.lock().await.clone();
+    let client = http_client.get().await;
+
+    if let Err(e) = settings.get_provider(&client) {
+        return Err(e.to_string());
+    }
+
+    if let Err(e) = conversation.save(&settings, &client).await {
         return Err(e.to_string());
     };
 
@@ -84,69 +223,371 @@ async fn list_conversations() -> Result<Vec<SerializedConversation>, String> {
     }
 }
 
+/// Returns the ids of the open conversations, oldest first.
 #[tauri::command]
-fn get_current_conversation_id(conversation: tauri::State<'_, Conversation>) -> Result<u32, ()> {
-    Ok(conversation.get_id())
+async fn list_open_conversations(
+    conversations: tauri::State<'_, ConversationManager>,
+) -> Result<Vec<u32>, ()> {
+    Ok(conversations.list_open().await)
 }
 
+/// Opens the saved conversation with `conversation_id` next to the open ones, its messages are
+/// sent with the `refresh_messages` event.
 #[tauri::command]
 async fn load_conversation(
-    conversation: tauri::State<'_, Conversation>,
+    conversations: tauri::State<'_, ConversationManager>,
+    window: tauri::Window,
+    conversation_id: u32,
+) -> Result<(), String> {
+    let conversation = match conversations.open(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let messages = conversation.get_messages().lock().await;
+    EventEmitter::new(&window, conversation_id).emit("refresh_messages", messages.get_path());
+
+    Ok(())
+}
+
+/// Opens a new, empty conversation and returns its id.
+#[tauri::command]
+async fn new_conversation(conversations: tauri::State<'_, ConversationManager>) -> Result<u32, ()> {
+    Ok(conversations.create().await)
+}
+
+/// Closes the conversation with `conversation_id`, a response that is being streamed is
+/// cancelled.
+#[tauri::command]
+async fn close_conversation(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+) -> Result<(), String> {
+    match conversations.close(conversation_id).await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Returns where every message that is shown stands among its alternatives.
+#[tauri::command]
+async fn list_branches(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+) -> Result<Vec<Branches>, String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    Ok(conversation.list_branches().await)
+}
+
+#[tauri::command]
+async fn switch_branch(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
     window: tauri::Window,
-    new_conversation_id: u64,
+    message_index: usize,
+    alternative: usize,
 ) -> Result<(), String> {
-    if let Err(e) = conversation.load(new_conversation_id).await {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if let Err(e) = conversation.switch_branch(message_index, alternative).await {
         return Err(e.to_string());
+    }
+
+    let messages = conversation.get_messages().lock().await;
+    EventEmitter::new(&window, conversation_id).emit("refresh_messages", messages.get_path());
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_message_excluded(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+    window: tauri::Window,
+    message_index: usize,
+    excluded: bool,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
     };
 
+    if let Err(e) = conversation.set_excluded(message_index, excluded).await {
+        return Err(e.to_string());
+    }
+
     let messages = conversation.get_messages().lock().await;
+    EventEmitter::new(&window, conversation_id).emit("refresh_messages", messages.get_path());
+    Ok(())
+}
 
-    window.emit("refresh_messages", &*messages).unwrap();
+#[tauri::command]
+async fn set_message_pinned(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+    window: tauri::Window,
+    message_index: usize,
+    pinned: bool,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if let Err(e) = conversation.set_pinned(message_index, pinned).await {
+        return Err(e.to_string());
+    }
 
+    let messages = conversation.get_messages().lock().await;
+    EventEmitter::new(&window, conversation_id).emit("refresh_messages", messages.get_path());
     Ok(())
 }
 
 #[tauri::command]
-async fn reset_conversation(
-    conversation: tauri::State<'_, Conversation>,
+async fn delete_message(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
     window: tauri::Window,
-) -> Result<(), ()> {
-    conversation.reset().await;
+    message_index: usize,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if let Err(e) = conversation.delete_message(message_index).await {
+        return Err(e.to_string());
+    }
+
     let messages = conversation.get_messages().lock().await;
-    window.emit("refresh_messages", &*messages).unwrap();
+    EventEmitter::new(&window, conversation_id).emit("refresh_messages", messages.get_path());
     Ok(())
 }
 
+#[tauri::command]
+async fn select_candidate(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+    settings: tauri::State<'_, Mutex<Settings>>,
+    http_client: tauri::State<'_, HttpClient>,
+    window: tauri::Window,
+    message_index: usize,
+    candidate_index: usize,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if let Err(e) = conversation
+        .select_candidate(message_index, candidate_index)
+        .await
+    {
+        return Err(e.to_string());
+    }
+
+    {
+        let messages = conversation.get_messages().lock().await;
+        EventEmitter::new(&window, conversation_id).emit("refresh_messages", messages.get_path());
+    }
+
+    let settings = settings.lock().await.clone();
+    if let Err(e) = conversation.save(&settings, &http_client.get().await).await {
+        return Err(e.to_string());
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_token_probabilities(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+    message_index: usize,
+) -> Result<Vec<TokenProbability>, String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    match conversation.get_token_probabilities(message_index).await {
+        Ok(probabilities) => Ok(probabilities),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[tauri::command]
+async fn get_conversation_stats(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+    settings: tauri::State<'_, Mutex<Settings>>,
+    models: tauri::State<'_, Mutex<ModelRegistry>>,
+) -> Result<ConversationStats, String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let settings = settings.lock().await.clone();
+    let model = settings.get_model(&*models.lock().await);
+    Ok(conversation.get_stats(&model).await)
+}
+
+#[tauri::command]
+async fn get_conversation_parameters(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+) -> Result<SamplingParameters, String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    Ok(conversation.get_parameters().await)
+}
+
+#[tauri::command]
+async fn set_conversation_parameters(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+    parameters: SamplingParameters,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    conversation.set_parameters(parameters).await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_response_format(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+) -> Result<Option<ResponseFormat>, String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    Ok(conversation.get_response_format().await)
+}
+
+#[tauri::command]
+async fn set_response_format(
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+    response_format: Option<ResponseFormat>,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    conversation.set_response_format(response_format).await;
+    Ok(())
+}
+
+/// Returns the models that can be used with `provider`.
+#[tauri::command]
+async fn list_models(
+    models: tauri::State<'_, Mutex<ModelRegistry>>,
+    provider: ProviderKind,
+) -> Result<Vec<Model>, ()> {
+    Ok(models.lock().await.list(&provider))
+}
+
+/// Adds the models `provider` lists on its models endpoint to the registry, and returns the models
+/// that can be used with it.
+#[tauri::command]
+async fn refresh_models(
+    settings: tauri::State<'_, Mutex<Settings>>,
+    models: tauri::State<'_, Mutex<ModelRegistry>>,
+    http_client: tauri::State<'_, HttpClient>,
+    provider: ProviderKind,
+) -> Result<Vec<Model>, String> {
+    let settings = settings.lock().await.clone();
+    let model_ids = match settings
+        .list_provider_models(&provider, &http_client.get().await)
+        .await
+    {
+        Ok(model_ids) => model_ids,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let mut models = models.lock().await;
+    models.add_discovered(&provider, model_ids);
+    Ok(models.list(&provider))
+}
+
 #[tauri::command]
 async fn cancel(
-    cancel_state: tauri::State<'_, CancelState>
-) -> Result<(), ()> {
-    cancel_state.transmit_cancel();
+    conversations: tauri::State<'_, ConversationManager>,
+    conversation_id: u32,
+) -> Result<(), String> {
+    let conversation = match conversations.get(conversation_id).await {
+        Ok(conversation) => conversation,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    conversation.cancel();
     Ok(())
 }
 
 fn main() {
     use settings::{get_settings, update_settings};
     // Load settings
-    let settings = Settings::load().expect("Failed to load settings");
+    let mut settings = Settings::load().expect("Failed to load settings");
+    let models = ModelRegistry::load();
+    settings.normalize_model(&models);
+    let http_client = settings
+        .get_network()
+        .build_client()
+        .expect("Failed to create the HTTP client, check the network settings");
 
     tauri::Builder::default()
         .invoke_handler(tauri::generate_handler![
             prompt,
+            continue_generation,
+            edit_message,
+            regenerate,
             cancel,
             clear_messages,
             get_settings,
             update_settings,
             save,
             list_conversations,
-            get_current_conversation_id,
+            list_open_conversations,
             load_conversation,
-            reset_conversation,
+            new_conversation,
+            close_conversation,
+            list_models,
+            refresh_models,
+            get_conversation_parameters,
+            set_conversation_parameters,
+            get_response_format,
+            set_response_format,
+            select_candidate,
+            list_branches,
+            switch_branch,
+            set_message_excluded,
+            set_message_pinned,
+            delete_message,
+            get_conversation_stats,
+            get_token_probabilities,
         ])
         //.manage(Arc::new(Mutex::new(settings)))
-        .manage(Conversation::new())
+        .manage(ConversationManager::new())
         .manage(Mutex::new(settings))
-        .manage(CancelState::new())
+        .manage(Mutex::new(models))
+        .manage(HttpClient::new(http_client))
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,278 @@
+use crate::gpt::Message;
+use serde::{Deserialize, Serialize};
+
+/// The messages of a conversation as a tree. Every message has a parent, and messages with the
+/// same parent are alternatives of each other, like an edited prompt and the original or a
+/// regenerated answer and the earlier attempt. Only the active path, which follows the active
+/// child from the root down, is shown and sent to the model. The methods that take an index
+/// work on the active path, like the methods of a `Vec`.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct MessageTree {
+    nodes: Vec<Node>,
+    /// The first message of the active path, there can be alternatives of the first prompt
+    active_root: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Node {
+    message: Message,
+    parent: Option<usize>,
+    /// The child that continues the active path, remembered for when a branch is switched back
+    active_child: Option<usize>,
+}
+
+/// Where a message of the active path stands among its alternatives.
+#[derive(Clone, Serialize)]
+pub struct Branches {
+    /// Which alternative is on the active path, oldest first
+    selected: usize,
+    count: usize,
+}
+
+impl From<Vec<Message>> for MessageTree {
+    /// Builds a tree without alternatives, this is how conversations used to be stored.
+    fn from(messages: Vec<Message>) -> Self {
+        let mut tree = Self::default();
+        for message in messages {
+            tree.push(message);
+        }
+        tree
+    }
+}
+
+impl MessageTree {
+    /// Returns the indices of the nodes on the active path.
+    fn get_path_indices(&self) -> Vec<usize> {
+        let mut path = vec![];
+        let mut next = self.active_root;
+        while let Some(index) = next {
+            path.push(index);
+            next = self.nodes[index].active_child;
+        }
+        path
+    }
+
+    /// Returns the alternatives of a message with `parent`, oldest first.
+    fn get_children(&self, parent: Option<usize>) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&index| self.nodes[index].parent == parent)
+            .collect()
+    }
+
+    fn set_active_child(&mut self, parent: Option<usize>, child: Option<usize>) {
+        match parent {
+            Some(parent) => self.nodes[parent].active_child = child,
+            None => self.active_root = child,
+        }
+    }
+
+    /// Returns a copy of the messages on the active path.
+    pub fn get_path(&self) -> Vec<Message> {
+        self.iter().cloned().collect()
+    }
+
+    /// Iterates over the messages on the active path.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Message> + ExactSizeIterator {
+        self.get_path_indices()
+            .into_iter()
+            .map(|index| &self.nodes[index].message)
+    }
+
+    /// Iterates over every message in the tree, including the ones on other branches.
+    pub fn iter_all(&self) -> impl Iterator<Item = &Message> {
+        self.nodes.iter().map(|node| &node.message)
+    }
+
+    pub fn len(&self) -> usize {
+        self.get_path_indices().len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Message> {
+        let node = *self.get_path_indices().get(index)?;
+        Some(&self.nodes[node].message)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Message> {
+        let node = *self.get_path_indices().get(index)?;
+        Some(&mut self.nodes[node].message)
+    }
+
+    pub fn last(&self) -> Option<&Message> {
+        let node = *self.get_path_indices().last()?;
+        Some(&self.nodes[node].message)
+    }
+
+    pub fn last_mut(&mut self) -> Option<&mut Message> {
+        let node = *self.get_path_indices().last()?;
+        Some(&mut self.nodes[node].message)
+    }
+
+    /// Adds `message` to the end of the active path. If the active path was cut short before,
+    /// the message becomes an alternative of the messages that were cut off.
+    pub fn push(&mut self, message: Message) {
+        let parent = self.get_path_indices().last().copied();
+        self.nodes.push(Node {
+            message,
+            parent,
+            active_child: None,
+        });
+        self.set_active_child(parent, Some(self.nodes.len() - 1));
+    }
+
+    /// Removes the last message of the active path. A message that was just added is removed
+    /// from the tree, an older one stays behind as an alternative.
+    pub fn pop(&mut self) -> Option<Message> {
+        let path = self.get_path_indices();
+        let last = *path.last()?;
+        self.set_active_child(self.nodes[last].parent, None);
+
+        if last == self.nodes.len() - 1 {
+            self.nodes.pop().map(|node| node.message)
+        } else {
+            Some(self.nodes[last].message.clone())
+        }
+    }
+
+    /// Cuts the active path short after `len` messages and returns copies of the messages that
+    /// were cut off. They stay in the tree, so the next message that is pushed starts a new
+    /// branch.
+    pub fn split_off(&mut self, len: usize) -> Vec<Message> {
+        let path = self.get_path_indices();
+        if len >= path.len() {
+            return vec![];
+        }
+        self.set_active_child(self.nodes[path[len]].parent, None);
+
+        path[len..]
+            .iter()
+            .map(|&index| self.nodes[index].message.clone())
+            .collect()
+    }
+
+    /// Returns where every message on the active path stands among its alternatives.
+    pub fn list_branches(&self) -> Vec<Branches> {
+        self.get_path_indices()
+            .into_iter()
+            .map(|index| {
+                let alternatives = self.get_children(self.nodes[index].parent);
+                Branches {
+                    selected: alternatives.iter().position(|&i| i == index).unwrap(),
+                    count: alternatives.len(),
+                }
+            })
+            .collect()
+    }
+
+    /// Replaces the message at `index` of the active path with its alternative `alternative`,
+    /// the path then continues the way it did when that alternative was last active. Returns
+    /// false if there is no such message or alternative.
+    pub fn switch_branch(&mut self, index: usize, alternative: usize) -> bool {
+        let node = match self.get_path_indices().get(index) {
+            Some(&node) => node,
+            None => return false,
+        };
+        let parent = self.nodes[node].parent;
+
+        match self.get_children(parent).get(alternative) {
+            Some(&child) => {
+                self.set_active_child(parent, Some(child));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::Role;
+
+    fn message(content: &str) -> Message {
+        Message::new(Role::user, content.to_string())
+    }
+
+    fn contents(tree: &MessageTree) -> Vec<&str> {
+        tree.iter().map(|message| message.get_content()).collect()
+    }
+
+    fn tree(contents: &[&str]) -> MessageTree {
+        contents
+            .iter()
+            .map(|content| message(content))
+            .collect::<Vec<Message>>()
+            .into()
+    }
+
+    #[test]
+    fn split_off_keeps_the_messages_as_a_branch() {
+        let mut tree = tree(&["a", "b", "c"]);
+
+        let cut_off = tree.split_off(1);
+        assert_eq!(
+            cut_off
+                .iter()
+                .map(|m| m.get_content())
+                .collect::<Vec<&str>>(),
+            ["b", "c"]
+        );
+        assert_eq!(contents(&tree), ["a"]);
+
+        tree.push(message("b2"));
+        assert_eq!(contents(&tree), ["a", "b2"]);
+        assert_eq!(tree.iter_all().count(), 4);
+        let branches = tree.list_branches();
+        assert_eq!((branches[1].selected, branches[1].count), (1, 2));
+    }
+
+    #[test]
+    fn split_off_past_the_end_changes_nothing() {
+        let mut tree = tree(&["a", "b"]);
+        assert!(tree.split_off(2).is_empty());
+        assert_eq!(contents(&tree), ["a", "b"]);
+    }
+
+    #[test]
+    fn pop_removes_a_new_message_and_keeps_an_old_one() {
+        let mut tree = tree(&["a", "b"]);
+        assert_eq!(tree.pop().unwrap().get_content(), "b");
+        assert_eq!(tree.iter_all().count(), 1);
+
+        // "b" is now older than "c", so popping it leaves it behind as an alternative
+        tree.push(message("b"));
+        tree.split_off(1);
+        tree.push(message("c"));
+        tree.switch_branch(1, 0);
+        assert_eq!(tree.pop().unwrap().get_content(), "b");
+        assert_eq!(contents(&tree), ["a"]);
+        assert_eq!(tree.iter_all().count(), 3);
+    }
+
+    #[test]
+    fn switch_branch_restores_the_rest_of_the_path() {
+        let mut tree = tree(&["a", "b", "c"]);
+        tree.split_off(1);
+        tree.push(message("b2"));
+        tree.push(message("c2"));
+
+        assert!(tree.switch_branch(1, 0));
+        assert_eq!(contents(&tree), ["a", "b", "c"]);
+        assert!(tree.switch_branch(1, 1));
+        assert_eq!(contents(&tree), ["a", "b2", "c2"]);
+
+        assert!(!tree.switch_branch(1, 2));
+        assert!(!tree.switch_branch(3, 0));
+        assert_eq!(contents(&tree), ["a", "b2", "c2"]);
+    }
+
+    #[test]
+    fn alternatives_of_the_first_message() {
+        let mut tree = tree(&["a", "b"]);
+        tree.split_off(0);
+        assert_eq!(tree.len(), 0);
+        tree.push(message("a2"));
+
+        assert!(tree.switch_branch(0, 0));
+        assert_eq!(contents(&tree), ["a", "b"]);
+    }
+}
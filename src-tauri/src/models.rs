@@ -0,0 +1,312 @@
+use crate::providers::ProviderKind;
+use crate::tokenizer::Encoding;
+use directories::BaseDirs;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+/// The catalog that ships with the app, see the file for the format.
+const BUNDLED_MODELS: &str = include_str!("../models.toml");
+
+/// A model and everything we need to know to talk to it and to bill it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Model {
+    /// The id the provider's API knows the model by
+    id: String,
+    /// Shown in the model picker instead of the id
+    #[serde(default)]
+    name: Option<String>,
+    provider: ProviderKind,
+    /// Names older versions of the app stored in the settings file
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<String>,
+    /// Maximum number of tokens the prompt and the response can take up together, if known
+    #[serde(default)]
+    context_window: Option<usize>,
+    /// Whether images can be attached
+    #[serde(default)]
+    vision: bool,
+    /// Whether the model can call tools
+    #[serde(default)]
+    tools: bool,
+    /// Whether the model reasons before it answers, see `Request::for_reasoning_model`
+    #[serde(default)]
+    reasoning: bool,
+    /// Whether the model accepts system or developer messages, see
+    /// `Request::without_system_messages`
+    #[serde(default = "default_system_messages")]
+    system_messages: bool,
+    /// Dollars per million input tokens, if known
+    #[serde(default)]
+    input_price: Option<f32>,
+    /// Dollars per million output tokens, if known
+    #[serde(default)]
+    output_price: Option<f32>,
+    /// Input tokens that were read from the provider's prompt cache are billed at this fraction
+    /// of the normal input price.
+    #[serde(default = "default_cached_input_factor")]
+    cached_input_factor: f32,
+    /// The encoding that is used to count tokens locally
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+fn default_cached_input_factor() -> f32 {
+    1.0
+}
+
+fn default_system_messages() -> bool {
+    true
+}
+
+impl Model {
+    /// Describes a model that isn't in the catalog, like a model served by Ollama or a custom
+    /// endpoint. We have no idea what it costs or what it supports, so its costs are estimated as
+    /// free and it gets no attachments or tools it might choke on.
+    pub fn unknown(id: &str, provider: ProviderKind) -> Self {
+        Self {
+            id: id.to_string(),
+            name: None,
+            provider,
+            aliases: vec![],
+            context_window: None,
+            vision: false,
+            tools: false,
+            reasoning: false,
+            system_messages: true,
+            input_price: None,
+            output_price: None,
+            cached_input_factor: 1.0,
+            encoding: Encoding::default(),
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the maximum number of tokens the prompt and the response can take up together,
+    /// or `None` if we don't know the model.
+    pub fn get_context_window(&self) -> Option<usize> {
+        self.context_window
+    }
+
+    pub fn get_encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn supports_vision(&self) -> bool {
+        self.vision
+    }
+
+    pub fn supports_tools(&self) -> bool {
+        self.tools
+    }
+
+    /// Whether this model reasons before it answers. Reasoning models need their own request
+    /// shape, see `Request::for_reasoning_model`.
+    pub fn is_reasoning_model(&self) -> bool {
+        self.reasoning
+    }
+
+    /// Whether the system prompt can be sent as a system or developer message. Otherwise it is
+    /// folded into the first prompt, see `Request::without_system_messages`.
+    pub fn supports_system_messages(&self) -> bool {
+        self.system_messages
+    }
+
+    /// Whether we know what the model costs. Costs of other models are calculated as free, so
+    /// they are only an estimate.
+    pub fn has_prices(&self) -> bool {
+        self.input_price.is_some() && self.output_price.is_some()
+    }
+
+    /// Calculates the cost in dollars of a request. `cached_tokens` is the part of
+    /// `prompt_tokens` that was read from the prompt cache, `completion_tokens` includes the
+    /// reasoning tokens. Unknown prices count as free, see `Model::has_prices`.
+    pub fn calculate_cost(
+        &self,
+        prompt_tokens: usize,
+        cached_tokens: usize,
+        completion_tokens: usize,
+    ) -> f32 {
+        let cached_tokens = cached_tokens.min(prompt_tokens);
+        let input_price = self.input_price.unwrap_or(0.0);
+        let output_price = self.output_price.unwrap_or(0.0);
+
+        ((prompt_tokens - cached_tokens) as f32 * input_price
+            + cached_tokens as f32 * input_price * self.cached_input_factor
+            + completion_tokens as f32 * output_price)
+            / 1_000_000.0
+    }
+
+    fn matches(&self, id: &str) -> bool {
+        self.id == id || self.aliases.iter().any(|alias| alias == id)
+    }
+}
+
+#[derive(Deserialize)]
+struct ModelFile {
+    models: Vec<Model>,
+}
+
+/// Every model the app knows about: the bundled catalog, the user's additions and whatever was
+/// discovered on the provider's models endpoint.
+pub struct ModelRegistry {
+    models: Vec<Model>,
+}
+
+impl ModelRegistry {
+    /// Loads the bundled catalog and applies the user's file on top of it. A broken user file is
+    /// reported and ignored, so it can't keep the app from starting.
+    pub fn load() -> Self {
+        let bundled: ModelFile =
+            toml::from_str(BUNDLED_MODELS).expect("Failed to parse the bundled model catalog");
+        let mut registry = Self {
+            models: bundled.models,
+        };
+
+        let user_file = Self::get_user_file();
+        if let Ok(contents) = fs::read_to_string(&user_file) {
+            match toml::from_str::<ModelFile>(&contents) {
+                Ok(user_models) => registry.insert_all(user_models.models),
+                Err(e) => eprintln!("Ignoring {}: {}", user_file.display(), e),
+            }
+        }
+
+        registry
+    }
+
+    /// Finds a model by its id, or by a name an older version of the app used for it.
+    pub fn get(&self, id: &str) -> Option<&Model> {
+        self.models.iter().find(|model| model.matches(id))
+    }
+
+    /// Returns the model with `id`, or a description of an unknown model if it isn't in the
+    /// catalog.
+    pub fn resolve(&self, id: &str, provider: &ProviderKind) -> Model {
+        self.get(id)
+            .cloned()
+            .unwrap_or_else(|| Model::unknown(id, provider.clone()))
+    }
+
+    /// Returns the models that can be used with `provider`. Azure serves OpenAI's models.
+    pub fn list(&self, provider: &ProviderKind) -> Vec<Model> {
+        let provider = match provider {
+            ProviderKind::Azure => &ProviderKind::OpenAi,
+            provider => provider,
+        };
+        self.models
+            .iter()
+            .filter(|model| model.provider == *provider)
+            .cloned()
+            .collect()
+    }
+
+    /// Adds the models that were listed by the provider's API, but aren't in the catalog yet.
+    pub fn add_discovered(&mut self, provider: &ProviderKind, ids: Vec<String>) {
+        for id in ids {
+            if self.get(&id).is_none() {
+                self.models.push(Model::unknown(&id, provider.clone()));
+            }
+        }
+    }
+
+    /// Adds `models`, replacing the models that have the same id.
+    fn insert_all(&mut self, models: Vec<Model>) {
+        for model in models {
+            match self.models.iter_mut().find(|known| known.id == model.id) {
+                Some(known) => *known = model,
+                None => self.models.push(model),
+            }
+        }
+    }
+
+    fn get_user_file() -> PathBuf {
+        let base_dirs = BaseDirs::new().expect("Failed to get base directories");
+        let mut user_file = base_dirs.config_dir().to_path_buf();
+        user_file.push(".chatgptauri-models.toml");
+        user_file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_models_replace_or_extend_the_catalog() {
+        let config_dir = std::env::temp_dir().join("chatgptauri-models-test");
+        fs::create_dir_all(&config_dir).unwrap();
+        std::env::set_var("XDG_CONFIG_HOME", &config_dir);
+        fs::write(
+            ModelRegistry::get_user_file(),
+            r#"
+                [[models]]
+                id = "gpt-4o"
+                provider = "openai"
+                input_price = 1.0
+                output_price = 2.0
+
+                [[models]]
+                id = "my-model"
+                provider = "ollama"
+                tools = true
+            "#,
+        )
+        .unwrap();
+
+        let registry = ModelRegistry::load();
+        fs::remove_dir_all(config_dir).unwrap();
+
+        // The entry is replaced as a whole, so the alias of the bundled entry is gone
+        let gpt_4o = registry.get("gpt-4o").unwrap();
+        assert_eq!(gpt_4o.input_price, Some(1.0));
+        assert!(gpt_4o.aliases.is_empty());
+
+        let my_model = registry.get("my-model").unwrap();
+        assert!(my_model.supports_tools());
+        assert!(!my_model.has_prices());
+        assert!(registry.get("gpt-4").is_some());
+    }
+
+    #[test]
+    fn resolves_aliases_and_unknown_models() {
+        let registry = ModelRegistry {
+            models: toml::from_str::<ModelFile>(BUNDLED_MODELS).unwrap().models,
+        };
+
+        assert_eq!(
+            registry.resolve("gpt4o", &ProviderKind::OpenAi).get_id(),
+            "gpt-4o"
+        );
+
+        let unknown = registry.resolve("llama3", &ProviderKind::Ollama);
+        assert_eq!(unknown.get_id(), "llama3");
+        assert!(!unknown.supports_vision());
+        assert!(!unknown.supports_tools());
+        assert!(!unknown.has_prices());
+        assert_eq!(unknown.calculate_cost(1000, 0, 1000), 0.0);
+    }
+
+    #[test]
+    fn adds_only_models_that_are_not_known_yet() {
+        let mut registry = ModelRegistry {
+            models: toml::from_str::<ModelFile>(BUNDLED_MODELS).unwrap().models,
+        };
+        let model_count = registry.models.len();
+
+        registry.add_discovered(
+            &ProviderKind::OpenAi,
+            vec!["gpt-4o".into(), "gpt4".into(), "gpt-5".into()],
+        );
+
+        assert_eq!(registry.models.len(), model_count + 1);
+        let discovered = registry.get("gpt-5").unwrap();
+        assert!(!discovered.has_prices());
+        assert!(registry
+            .list(&ProviderKind::OpenAi)
+            .iter()
+            .any(|model| model.get_id() == "gpt-5"));
+    }
+}
@@ -0,0 +1,478 @@
+use super::{build_request, skip_empty, stream_events, Auth};
+use super::{ChatProvider, DeltaStream, ProviderError};
+use crate::gpt::{
+    ApiConfig, ApiContent, ApiError, FinishReason, MessageDelta, Request, Role, StreamError, Usage,
+};
+use serde::Serialize;
+
+pub const DEFAULT_BASE_URL: &str = "https://api.anthropic.com/v1";
+const API_VERSION: &str = "2023-06-01";
+
+/// The Messages API requires an upper bound on the response length.
+const MAX_TOKENS: usize = 4096;
+
+/// Talks to the Anthropic Messages API.
+pub struct AnthropicProvider {
+    config: ApiConfig,
+}
+
+#[derive(Serialize, Debug)]
+struct MessagesRequest {
+    model: String,
+    max_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicMessage {
+    role: Role,
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text { text: String },
+    Image { source: ImageSource },
+}
+
+#[derive(Serialize, Debug)]
+struct ImageSource {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    media_type: String,
+    data: String,
+}
+
+impl From<ApiContent> for Vec<ContentBlock> {
+    /// Anthropic wants images before the text that refers to them.
+    fn from(content: ApiContent) -> Self {
+        let mut blocks: Vec<ContentBlock> = content
+            .get_images()
+            .into_iter()
+            .map(|image| ContentBlock::Image {
+                source: ImageSource {
+                    kind: "base64",
+                    media_type: image.mime_type.to_string(),
+                    data: image.data.to_string(),
+                },
+            })
+            .collect();
+        blocks.push(ContentBlock::Text {
+            text: content.get_text(),
+        });
+        blocks
+    }
+}
+
+impl MessagesRequest {
+    /// Moves the system messages out of the message list into the top-level `system` field, the
+    /// Messages API does not accept a system role. The roles have to alternate, so consecutive
+    /// messages of the same role, like two prompts around a failed response, are merged.
+    /// Penalties, seeds and response formats are not supported by Anthropic and are dropped.
+    fn from_request(request: Request) -> Self {
+        let mut system_prompts: Vec<String> = vec![];
+        let mut messages: Vec<AnthropicMessage> = vec![];
+
+        for message in skip_empty(request.messages) {
+            match message.role {
+                Role::system | Role::developer => system_prompts.push(message.content.get_text()),
+                // Tools are only supported through the OpenAI provider
+                Role::tool => continue,
+                role => {
+                    let content: Vec<ContentBlock> = message.content.into();
+                    match messages.last_mut() {
+                        Some(last) if last.role == role => last.content.extend(content),
+                        _ => messages.push(AnthropicMessage { role, content }),
+                    }
+                }
+            }
+        }
+
+        Self {
+            model: request.model,
+            max_tokens: request.parameters.max_tokens.unwrap_or(MAX_TOKENS),
+            system: match system_prompts.is_empty() {
+                true => None,
+                false => Some(system_prompts.join("\n\n")),
+            },
+            messages,
+            stream: request.stream,
+            temperature: request.parameters.temperature,
+            top_p: request.parameters.top_p,
+            stop_sequences: request.parameters.stop,
+        }
+    }
+}
+
+impl AnthropicProvider {
+    pub fn new(config: ApiConfig) -> Self {
+        Self { config }
+    }
+
+    /// Classifies an error response, Anthropic responds with
+    /// `{"type": "error", "error": {"type": ..., "message": ...}}`.
+    fn parse_error(status: reqwest::StatusCode, body: Option<&str>) -> ApiError {
+        match body.and_then(|body| serde_json::from_str::<anthropic_types::ErrorBody>(body).ok()) {
+            Some(body) => Self::to_api_error(body.error),
+            None => ApiError::from_response(status, body),
+        }
+    }
+
+    /// Errors can also arrive as an event in the middle of the stream, after the response
+    /// started.
+    fn to_api_error(error: anthropic_types::ApiError) -> ApiError {
+        match error.kind.as_str() {
+            "authentication_error" => ApiError::InvalidKey,
+            "rate_limit_error" => ApiError::RateLimited,
+            "api_error" => ApiError::ServerError(500),
+            "overloaded_error" => ApiError::ServerError(529),
+            "not_found_error" => ApiError::ModelNotFound(error.message),
+            "invalid_request_error" if error.message.starts_with("prompt is too long") => {
+                ApiError::ContextTooLong
+            }
+            "invalid_request_error" if error.message.contains("credit balance is too low") => {
+                ApiError::QuotaExhausted
+            }
+            _ => ApiError::Other(error.message),
+        }
+    }
+
+    /// The usage is split over the `message_start` and `message_delta` events, the latter also
+    /// carries the stop reason.
+    fn process_event(event: eventsource_stream::Event) -> Result<Vec<MessageDelta>, StreamError> {
+        let data: anthropic_types::EventData = serde_json::from_str(&event.data)?;
+
+        match data {
+            anthropic_types::EventData::MessageStart { message } => {
+                // `input_tokens` doesn't include the tokens that were read from or written to the
+                // prompt cache
+                let usage = message.usage;
+                Ok(vec![MessageDelta::Usage(Usage {
+                    prompt_tokens: Some(
+                        usage.input_tokens
+                            + usage.cache_creation_input_tokens
+                            + usage.cache_read_input_tokens,
+                    ),
+                    cached_tokens: Some(usage.cache_read_input_tokens),
+                    completion_tokens: Some(usage.output_tokens),
+                    reasoning_tokens: None,
+                })])
+            }
+            anthropic_types::EventData::ContentBlockDelta { delta } => match delta {
+                anthropic_types::ContentDelta::TextDelta { text } => {
+                    Ok(vec![MessageDelta::Delta(0, text)])
+                }
+                anthropic_types::ContentDelta::Other => Ok(vec![MessageDelta::NoData]),
+            },
+            anthropic_types::EventData::MessageDelta { delta, usage } => {
+                // Output tokens are cumulative, so this is the final count
+                let mut deltas = vec![MessageDelta::Usage(Usage {
+                    prompt_tokens: None,
+                    cached_tokens: None,
+                    completion_tokens: Some(usage.output_tokens),
+                    reasoning_tokens: None,
+                })];
+                if let Some(stop_reason) = delta.stop_reason {
+                    deltas.push(MessageDelta::FinishReason(
+                        0,
+                        FinishReason::from_provider(&stop_reason),
+                    ));
+                }
+                Ok(deltas)
+            }
+            anthropic_types::EventData::MessageStop => Ok(vec![MessageDelta::Done]),
+            anthropic_types::EventData::Error { error } => Err(Self::to_api_error(error).into()),
+            anthropic_types::EventData::Other => Ok(vec![MessageDelta::NoData]),
+        }
+    }
+}
+
+impl ChatProvider for AnthropicProvider {
+    fn stream_chat(&self, request: Request) -> Result<DeltaStream, ProviderError> {
+        let request_builder = build_request(
+            &self.config,
+            &Auth::Header("x-api-key"),
+            reqwest::Method::POST,
+            "messages",
+        )
+        .header("anthropic-version", API_VERSION)
+        .json(&MessagesRequest::from_request(request));
+
+        Ok(stream_events(
+            request_builder,
+            Self::process_event,
+            Self::parse_error,
+        ))
+    }
+}
+
+mod anthropic_types {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum EventData {
+        MessageStart {
+            message: MessageStart,
+        },
+        ContentBlockDelta {
+            delta: ContentDelta,
+        },
+        MessageDelta {
+            delta: StopDelta,
+            usage: OutputUsage,
+        },
+        MessageStop,
+        Error {
+            error: ApiError,
+        },
+        /// `ping`, `content_block_start` and `content_block_stop` don't carry anything we need
+        #[serde(other)]
+        Other,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct MessageStart {
+        pub usage: InputUsage,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct InputUsage {
+        pub input_tokens: usize,
+        #[serde(default)]
+        pub output_tokens: usize,
+        #[serde(default)]
+        pub cache_creation_input_tokens: usize,
+        #[serde(default)]
+        pub cache_read_input_tokens: usize,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct StopDelta {
+        pub stop_reason: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct OutputUsage {
+        pub output_tokens: usize,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum ContentDelta {
+        TextDelta {
+            text: String,
+        },
+        #[serde(other)]
+        Other,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ErrorBody {
+        pub error: ApiError,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ApiError {
+        #[serde(rename = "type")]
+        pub kind: String,
+        pub message: String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::Message;
+    use crate::providers::test_server;
+    use reqwest::StatusCode;
+    use serde_json::json;
+    use tokio_stream::StreamExt;
+
+    fn event(data: &str) -> String {
+        format!("event: message\ndata: {}\n\n", data)
+    }
+
+    /// Streams `events` and returns the deltas as JSON, so they can be compared.
+    async fn stream(events: &[&str]) -> serde_json::Value {
+        let chunks: Vec<String> = events.iter().map(|data| event(data)).collect();
+        let chunks: Vec<&str> = chunks.iter().map(String::as_str).collect();
+        let provider = AnthropicProvider::new(test_server::serve(200, &chunks).await);
+        let request = Request::new(vec![Message::new(Role::user, "Hi".to_string())], "claude");
+
+        let deltas: Vec<MessageDelta> = provider
+            .stream_chat(request)
+            .unwrap()
+            .map(|delta| delta.unwrap())
+            .filter(|delta| !matches!(delta, MessageDelta::NoData))
+            .collect()
+            .await;
+        serde_json::to_value(deltas).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_text_usage_and_stop_reason() {
+        let deltas = stream(&[
+            r#"{"type":"message_start","message":{"id":"msg_1","usage":{"input_tokens":10,"cache_read_input_tokens":20,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":" world"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"max_tokens"},"usage":{"output_tokens":2}}"#,
+            r#"{"type":"message_stop"}"#,
+        ])
+        .await;
+
+        // The cached tokens are part of the prompt. The end of the stream adds a second `Done`,
+        // which is never read.
+        assert_eq!(
+            deltas,
+            json!([
+                {"Usage": {"prompt_tokens": 30, "cached_tokens": 20, "completion_tokens": 1, "reasoning_tokens": null}},
+                {"Delta": [0, "Hello"]},
+                {"Delta": [0, " world"]},
+                {"Usage": {"prompt_tokens": null, "cached_tokens": null, "completion_tokens": 2, "reasoning_tokens": null}},
+                {"FinishReason": [0, "length"]},
+                "Done",
+                "Done",
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_an_error_event_as_error() {
+        let config = test_server::serve(
+            200,
+            &[&event(
+                r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#,
+            )],
+        )
+        .await;
+        let provider = AnthropicProvider::new(config);
+        let request = Request::new(vec![Message::new(Role::user, "Hi".to_string())], "claude");
+        let mut deltas = provider.stream_chat(request).unwrap();
+
+        assert!(matches!(
+            deltas.next().await,
+            Some(Err(StreamError::ApiError(ApiError::ServerError(529))))
+        ));
+    }
+
+    #[test]
+    fn moves_system_prompts_to_the_top_level() {
+        let mut request = Request::new(
+            vec![
+                Message::new(Role::user, "Hi".to_string()),
+                Message::new(Role::assistant, "Hello".to_string()),
+                Message::new(Role::user, "Bye".to_string()),
+            ],
+            "claude",
+        );
+        request.parameters.max_tokens = Some(100);
+        let request = serde_json::to_value(MessagesRequest::from_request(request)).unwrap();
+
+        assert_eq!(request["system"], crate::gpt::SYSTEM_PROMPT);
+        assert_eq!(request["max_tokens"], 100);
+        assert_eq!(
+            request["messages"],
+            json!([
+                {"role": "user", "content": [{"type": "text", "text": "Hi"}]},
+                {"role": "assistant", "content": [{"type": "text", "text": "Hello"}]},
+                {"role": "user", "content": [{"type": "text", "text": "Bye"}]},
+            ])
+        );
+    }
+
+    #[test]
+    fn merges_consecutive_messages_of_the_same_role() {
+        let request = Request::new(
+            vec![
+                Message::new(Role::user, "Hi".to_string()),
+                Message::new(Role::user, "Anyone there?".to_string()),
+            ],
+            "claude",
+        );
+        let request = serde_json::to_value(MessagesRequest::from_request(request)).unwrap();
+
+        assert_eq!(request["max_tokens"], MAX_TOKENS);
+        assert_eq!(
+            request["messages"],
+            json!([{"role": "user", "content": [
+                {"type": "text", "text": "Hi"},
+                {"type": "text", "text": "Anyone there?"},
+            ]}])
+        );
+    }
+
+    fn parse(status: StatusCode, kind: &str, message: &str) -> ApiError {
+        let body = serde_json::json!({
+            "type": "error",
+            "error": { "type": kind, "message": message },
+        })
+        .to_string();
+        AnthropicProvider::parse_error(status, Some(&body))
+    }
+
+    #[test]
+    fn classifies_error_responses() {
+        assert!(matches!(
+            parse(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "prompt is too long: 210000 tokens > 200000 maximum"
+            ),
+            ApiError::ContextTooLong
+        ));
+        assert!(matches!(
+            parse(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "Your credit balance is too low to access the Anthropic API"
+            ),
+            ApiError::QuotaExhausted
+        ));
+        assert!(matches!(
+            parse(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "max_tokens: Field required"
+            ),
+            ApiError::Other(_)
+        ));
+        assert!(matches!(
+            parse(
+                StatusCode::UNAUTHORIZED,
+                "authentication_error",
+                "invalid x-api-key"
+            ),
+            ApiError::InvalidKey
+        ));
+        assert!(matches!(
+            parse(StatusCode::NOT_FOUND, "not_found_error", "model: claude-9"),
+            ApiError::ModelNotFound(_)
+        ));
+        assert!(matches!(
+            parse(
+                StatusCode::from_u16(529).unwrap(),
+                "overloaded_error",
+                "Overloaded"
+            ),
+            ApiError::ServerError(529)
+        ));
+        assert!(matches!(
+            AnthropicProvider::parse_error(StatusCode::BAD_GATEWAY, None),
+            ApiError::ServerError(502)
+        ));
+    }
+}
@@ -0,0 +1,457 @@
+use super::{build_request, skip_empty, stream_events, Auth};
+use super::{ChatProvider, DeltaStream, ProviderError};
+use crate::gpt::{
+    ApiConfig, ApiContent, ApiError, FinishReason, MessageDelta, Request, ResponseFormat, Role,
+    SamplingParameters, StreamError, Usage,
+};
+use serde::Serialize;
+
+pub const DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// Talks to the Google Gemini API using `streamGenerateContent` with SSE.
+pub struct GeminiProvider {
+    config: ApiConfig,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GenerateContentRequest {
+    contents: Vec<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<Content>,
+    generation_config: GenerationConfig,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidate_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<serde_json::Value>,
+}
+
+impl GenerationConfig {
+    /// Gemini doesn't have a response format, JSON mode is enabled by setting the mime type.
+    fn set_response_format(&mut self, response_format: Option<ResponseFormat>) {
+        match response_format {
+            Some(ResponseFormat::JsonObject) => {
+                self.response_mime_type = Some("application/json");
+            }
+            Some(ResponseFormat::JsonSchema { json_schema }) => {
+                self.response_mime_type = Some("application/json");
+                self.response_schema = Some(json_schema.schema);
+            }
+            None => {}
+        }
+    }
+}
+
+impl From<SamplingParameters> for GenerationConfig {
+    fn from(parameters: SamplingParameters) -> Self {
+        Self {
+            temperature: parameters.temperature,
+            top_p: parameters.top_p,
+            max_output_tokens: parameters.max_tokens,
+            presence_penalty: parameters.presence_penalty,
+            frequency_penalty: parameters.frequency_penalty,
+            stop_sequences: parameters.stop,
+            seed: parameters.seed,
+            candidate_count: None,
+            response_mime_type: None,
+            response_schema: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Content {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    parts: Vec<Part>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum Part {
+    Text {
+        text: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    InlineData {
+        inline_data: InlineData,
+    },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct InlineData {
+    mime_type: String,
+    data: String,
+}
+
+impl From<ApiContent> for Vec<Part> {
+    fn from(content: ApiContent) -> Self {
+        let mut parts: Vec<Part> = vec![Part::Text {
+            text: content.get_text(),
+        }];
+        parts.extend(
+            content
+                .get_images()
+                .into_iter()
+                .map(|image| Part::InlineData {
+                    inline_data: InlineData {
+                        mime_type: image.mime_type.to_string(),
+                        data: image.data.to_string(),
+                    },
+                }),
+        );
+        parts
+    }
+}
+
+impl GenerateContentRequest {
+    /// Gemini calls the assistant `model` and takes the system prompt as a separate
+    /// `systemInstruction`. Consecutive messages of the same role are merged, because the roles
+    /// have to alternate.
+    fn from_request(request: Request) -> Self {
+        let mut system_parts: Vec<Part> = vec![];
+        let mut contents: Vec<Content> = vec![];
+
+        for message in skip_empty(request.messages) {
+            let role = match message.role {
+                Role::system | Role::developer => {
+                    system_parts.push(Part::Text {
+                        text: message.content.get_text(),
+                    });
+                    continue;
+                }
+                Role::user => "user",
+                Role::assistant => "model",
+                // Tools are only supported through the OpenAI provider, errors are never sent
+                Role::tool | Role::error => continue,
+            };
+
+            let parts: Vec<Part> = message.content.into();
+            match contents.last_mut() {
+                Some(last) if last.role == Some(role) => last.parts.extend(parts),
+                _ => contents.push(Content {
+                    role: Some(role),
+                    parts,
+                }),
+            }
+        }
+
+        let mut generation_config: GenerationConfig = request.parameters.into();
+        generation_config.set_response_format(request.response_format);
+        generation_config.candidate_count = request.n;
+
+        Self {
+            contents,
+            system_instruction: match system_parts.is_empty() {
+                true => None,
+                false => Some(Content {
+                    role: None,
+                    parts: system_parts,
+                }),
+            },
+            generation_config,
+        }
+    }
+}
+
+impl GeminiProvider {
+    pub fn new(config: ApiConfig) -> Self {
+        Self { config }
+    }
+
+    /// Classifies an error response, Gemini responds with
+    /// `{"error": {"code": ..., "message": ..., "status": ..., "details": [...]}}`.
+    fn parse_error(status: reqwest::StatusCode, body: Option<&str>) -> ApiError {
+        match body.and_then(|body| serde_json::from_str::<gemini_types::ErrorBody>(body).ok()) {
+            Some(body) => Self::to_api_error(body.error),
+            None => ApiError::from_response(status, body),
+        }
+    }
+
+    /// An invalid key is a 400 `INVALID_ARGUMENT`, only the reason in the details tells it apart
+    /// from other invalid requests.
+    fn to_api_error(error: gemini_types::ApiError) -> ApiError {
+        let reason = error
+            .details
+            .iter()
+            .find_map(|detail| detail.reason.as_deref());
+
+        match (error.status.as_deref(), reason) {
+            (_, Some("API_KEY_INVALID")) | (Some("UNAUTHENTICATED"), _) => ApiError::InvalidKey,
+            (Some("RESOURCE_EXHAUSTED"), _) => ApiError::RateLimited,
+            (Some("NOT_FOUND"), _) => ApiError::ModelNotFound(error.message),
+            (Some("INVALID_ARGUMENT"), _)
+                if error
+                    .message
+                    .contains("exceeds the maximum number of tokens") =>
+            {
+                ApiError::ContextTooLong
+            }
+            _ if error.code >= 500 => ApiError::ServerError(error.code),
+            _ => ApiError::Other(error.message),
+        }
+    }
+
+    /// Every event has the text of each candidate and the usage so far, the last one also has the
+    /// finish reasons.
+    fn process_event(event: eventsource_stream::Event) -> Result<Vec<MessageDelta>, StreamError> {
+        let data: gemini_types::EventData = serde_json::from_str(&event.data)?;
+        let mut deltas: Vec<MessageDelta> = vec![];
+
+        if let Some(error) = data.error {
+            return Err(Self::to_api_error(error).into());
+        }
+
+        for candidate in &data.candidates {
+            if let Some(content) = &candidate.content {
+                for part in &content.parts {
+                    if let Some(text) = &part.text {
+                        deltas.push(MessageDelta::Delta(candidate.index, text.clone()));
+                    }
+                }
+            }
+            if let Some(finish_reason) = &candidate.finish_reason {
+                deltas.push(MessageDelta::FinishReason(
+                    candidate.index,
+                    FinishReason::from_provider(finish_reason),
+                ));
+            }
+        }
+
+        if let Some(usage) = data.usage_metadata {
+            deltas.push(MessageDelta::Usage(Usage {
+                prompt_tokens: usage.prompt_token_count,
+                cached_tokens: usage.cached_content_token_count,
+                completion_tokens: usage.candidates_token_count,
+                reasoning_tokens: None,
+            }));
+        }
+
+        Ok(deltas)
+    }
+}
+
+impl ChatProvider for GeminiProvider {
+    fn stream_chat(&self, request: Request) -> Result<DeltaStream, ProviderError> {
+        let path = format!("models/{}:streamGenerateContent?alt=sse", request.model);
+        let request_builder = build_request(
+            &self.config,
+            &Auth::Header("x-goog-api-key"),
+            reqwest::Method::POST,
+            &path,
+        )
+        .json(&GenerateContentRequest::from_request(request));
+
+        Ok(stream_events(
+            request_builder,
+            Self::process_event,
+            Self::parse_error,
+        ))
+    }
+
+    fn supports_choice_count(&self) -> bool {
+        true
+    }
+}
+
+mod gemini_types {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct EventData {
+        #[serde(default)]
+        pub candidates: Vec<Candidate>,
+        pub usage_metadata: Option<UsageMetadata>,
+        pub error: Option<ApiError>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Candidate {
+        pub content: Option<Content>,
+        #[serde(default)]
+        pub index: usize,
+        pub finish_reason: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Content {
+        #[serde(default)]
+        pub parts: Vec<Part>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Part {
+        pub text: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct UsageMetadata {
+        pub prompt_token_count: Option<usize>,
+        /// The part of the prompt that was read from a cached context
+        pub cached_content_token_count: Option<usize>,
+        pub candidates_token_count: Option<usize>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ErrorBody {
+        pub error: ApiError,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ApiError {
+        /// The HTTP status code
+        #[serde(default)]
+        pub code: u16,
+        pub message: String,
+        /// Like `INVALID_ARGUMENT` or `RESOURCE_EXHAUSTED`
+        pub status: Option<String>,
+        #[serde(default)]
+        pub details: Vec<ErrorDetail>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ErrorDetail {
+        /// Like `API_KEY_INVALID`
+        pub reason: Option<String>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::Message;
+    use crate::providers::test_server;
+    use reqwest::StatusCode;
+    use serde_json::json;
+    use tokio_stream::StreamExt;
+
+    /// Streams `events` and returns the deltas as JSON, so they can be compared.
+    async fn stream(events: &[&str]) -> serde_json::Value {
+        let chunks: Vec<String> = events
+            .iter()
+            .map(|data| format!("data: {}\r\n\r\n", data))
+            .collect();
+        let chunks: Vec<&str> = chunks.iter().map(String::as_str).collect();
+        let provider = GeminiProvider::new(test_server::serve(200, &chunks).await);
+        let request = Request::new(vec![Message::new(Role::user, "Hi".to_string())], "gemini");
+
+        let deltas: Vec<MessageDelta> = provider
+            .stream_chat(request)
+            .unwrap()
+            .map(|delta| delta.unwrap())
+            .collect()
+            .await;
+        serde_json::to_value(deltas).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_every_candidate_and_the_usage() {
+        let deltas = stream(&[
+            r#"{"candidates":[{"content":{"parts":[{"text":"Hello"}],"role":"model"},"index":0},{"content":{"parts":[{"text":"Hi"}],"role":"model"},"index":1}],"usageMetadata":{"promptTokenCount":8}}"#,
+            r#"{"candidates":[{"content":{"parts":[{"text":" world"}],"role":"model"},"finishReason":"STOP","index":0},{"finishReason":"MAX_TOKENS","index":1}],"usageMetadata":{"promptTokenCount":8,"cachedContentTokenCount":4,"candidatesTokenCount":3}}"#,
+        ])
+        .await;
+
+        assert_eq!(
+            deltas,
+            json!([
+                {"Delta": [0, "Hello"]},
+                {"Delta": [1, "Hi"]},
+                {"Usage": {"prompt_tokens": 8, "cached_tokens": null, "completion_tokens": null, "reasoning_tokens": null}},
+                {"Delta": [0, " world"]},
+                {"FinishReason": [0, "stop"]},
+                {"FinishReason": [1, "length"]},
+                {"Usage": {"prompt_tokens": 8, "cached_tokens": 4, "completion_tokens": 3, "reasoning_tokens": null}},
+                "Done",
+            ])
+        );
+    }
+
+    #[test]
+    fn translates_roles_and_the_system_prompt() {
+        let mut request = Request::new(
+            vec![
+                Message::new(Role::user, "Hi".to_string()),
+                Message::new(Role::assistant, "Hello".to_string()),
+                Message::new(Role::user, "Bye".to_string()),
+                Message::new(Role::user, "Anyone there?".to_string()),
+            ],
+            "gemini",
+        )
+        .with_choice_count(2);
+        request.parameters.max_tokens = Some(100);
+        let request = serde_json::to_value(GenerateContentRequest::from_request(request)).unwrap();
+
+        assert_eq!(
+            request["systemInstruction"],
+            json!({"parts": [{"text": crate::gpt::SYSTEM_PROMPT}]})
+        );
+        assert_eq!(
+            request["contents"],
+            json!([
+                {"role": "user", "parts": [{"text": "Hi"}]},
+                {"role": "model", "parts": [{"text": "Hello"}]},
+                {"role": "user", "parts": [{"text": "Bye"}, {"text": "Anyone there?"}]},
+            ])
+        );
+        assert_eq!(
+            request["generationConfig"],
+            json!({"maxOutputTokens": 100, "candidateCount": 2})
+        );
+    }
+
+    #[test]
+    fn classifies_error_responses() {
+        let invalid_key = r#"{"error":{"code":400,"message":"API key not valid. Please pass a valid API key.","status":"INVALID_ARGUMENT","details":[{"@type":"type.googleapis.com/google.rpc.ErrorInfo","reason":"API_KEY_INVALID","domain":"googleapis.com"}]}}"#;
+        let too_long = r#"{"error":{"code":400,"message":"The input token count (1200000) exceeds the maximum number of tokens allowed (1048576).","status":"INVALID_ARGUMENT"}}"#;
+        let not_found = r#"{"error":{"code":404,"message":"models/gemini-9 is not found for API version v1beta","status":"NOT_FOUND"}}"#;
+        let exhausted = r#"{"error":{"code":429,"message":"Resource has been exhausted (e.g. check quota).","status":"RESOURCE_EXHAUSTED"}}"#;
+        let invalid = r#"{"error":{"code":400,"message":"Invalid value at 'contents[0].role'","status":"INVALID_ARGUMENT"}}"#;
+
+        assert!(matches!(
+            GeminiProvider::parse_error(StatusCode::BAD_REQUEST, Some(invalid_key)),
+            ApiError::InvalidKey
+        ));
+        assert!(matches!(
+            GeminiProvider::parse_error(StatusCode::BAD_REQUEST, Some(too_long)),
+            ApiError::ContextTooLong
+        ));
+        assert!(matches!(
+            GeminiProvider::parse_error(StatusCode::NOT_FOUND, Some(not_found)),
+            ApiError::ModelNotFound(_)
+        ));
+        assert!(matches!(
+            GeminiProvider::parse_error(StatusCode::TOO_MANY_REQUESTS, Some(exhausted)),
+            ApiError::RateLimited
+        ));
+        assert!(matches!(
+            GeminiProvider::parse_error(StatusCode::BAD_REQUEST, Some(invalid)),
+            ApiError::Other(_)
+        ));
+    }
+}
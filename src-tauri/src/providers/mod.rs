@@ -0,0 +1,249 @@
+use crate::gpt::{
+    get_retry_after, ApiConfig, ApiError, ApiMessage, MessageDelta, Request, StreamError,
+};
+use eventsource_stream::Eventsource;
+use futures_core::Stream;
+use reqwest_eventsource::{self as reqwest_es, CannotCloneRequestError};
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use thiserror::Error;
+use tokio_stream::StreamExt;
+
+pub mod anthropic;
+pub mod gemini;
+pub mod ollama;
+pub mod openai;
+
+pub type DeltaStream = Pin<Box<dyn Stream<Item = Result<MessageDelta, StreamError>> + Send>>;
+
+/// A chat API that can turn a `Request` into a stream of `MessageDelta`s. Everything that is
+/// specific to a vendor's wire format (authentication, request body, event format) lives in the
+/// implementation, so the conversation only ever has to deal with deltas.
+pub trait ChatProvider: Send + Sync {
+    /// Starts a streaming chat completion.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request cannot be started.
+    fn stream_chat(&self, request: Request) -> Result<DeltaStream, ProviderError>;
+
+    /// Whether `Request.tools` is sent to the API and tool calls are streamed back.
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    /// Whether the provider can generate multiple choices for a single request (`Request.n`).
+    fn supports_choice_count(&self) -> bool {
+        false
+    }
+
+    /// Whether token log probabilities can be requested (`Request.logprobs`).
+    fn supports_logprobs(&self) -> bool {
+        false
+    }
+}
+
+/// Turns a single server-sent event into the deltas it contains. One event can carry several
+/// things at once, like content and the final usage, so every delta that is in it is returned.
+type ProcessEvent = fn(eventsource_stream::Event) -> Result<Vec<MessageDelta>, StreamError>;
+
+/// Classifies an unsuccessful response by its status code and its body, every vendor describes
+/// errors in its own way.
+type ParseError = fn(reqwest::StatusCode, Option<&str>) -> ApiError;
+
+/// How the API key is sent to the server.
+pub enum Auth {
+    /// `Authorization: Bearer <key>`, used by OpenAI and most compatible servers
+    Bearer,
+    /// The key as is, in the header with this name, like Azure's `api-key`
+    Header(&'static str),
+}
+
+/// Creates a request to `path` of the API in `config`, with the authentication and the extra
+/// headers.
+fn build_request(
+    config: &ApiConfig,
+    auth: &Auth,
+    method: reqwest::Method,
+    path: &str,
+) -> reqwest::RequestBuilder {
+    let mut request_builder = config.client.request(method, config.get_url(path));
+    if let Some(api_key) = &config.api_key {
+        request_builder = match auth {
+            Auth::Bearer => request_builder.header("Authorization", format!("Bearer {}", api_key)),
+            Auth::Header(name) => request_builder.header(*name, api_key),
+        };
+    }
+    for (name, value) in &config.headers {
+        request_builder = request_builder.header(name, value);
+    }
+    request_builder
+}
+
+/// Turns the result of processing a single event into items of a `DeltaStream`.
+fn into_stream_items(
+    deltas: Result<Vec<MessageDelta>, StreamError>,
+) -> Vec<Result<MessageDelta, StreamError>> {
+    match deltas {
+        Ok(deltas) => deltas.into_iter().map(Ok).collect(),
+        Err(e) => vec![Err(e)],
+    }
+}
+
+/// Leaves out the messages without content. APIs other than OpenAI reject them, but the
+/// conversation sends an empty assistant message that the response will be written to.
+fn skip_empty(messages: Vec<ApiMessage>) -> impl Iterator<Item = ApiMessage> {
+    messages
+        .into_iter()
+        .filter(|message| !message.content.is_empty())
+}
+
+/// Sends `request_builder` and checks the status of the response. The body of an error response
+/// tells us what went wrong, so it is read and classified with `parse_error`.
+///
+/// # Errors
+///
+/// This function will return an error if the server can't be reached or responds with an error.
+async fn send(
+    request_builder: reqwest::RequestBuilder,
+    parse_error: ParseError,
+) -> Result<reqwest::Response, StreamError> {
+    let response = request_builder.send().await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = get_retry_after(response.headers());
+    let body = response.text().await.ok();
+    Err(StreamError::ErrorResponse {
+        error: parse_error(status, body.as_deref()),
+        retry_after,
+    })
+}
+
+/// Sends `request_builder` and streams the server-sent events of the response through
+/// `process_event`. Some APIs, like Gemini, have no end of stream event and just close the
+/// connection, so a `MessageDelta::Done` is sent when the response ends.
+///
+/// The response is read by hand instead of through an `EventSource`, which throws away the body
+/// of an error response.
+fn stream_events(
+    request_builder: reqwest::RequestBuilder,
+    process_event: ProcessEvent,
+    parse_error: ParseError,
+) -> DeltaStream {
+    Box::pin(async_stream::stream! {
+        let response = match send(request_builder, parse_error).await {
+            Ok(response) => response,
+            Err(e) => {
+                yield Err(e);
+                return;
+            }
+        };
+
+        let mut events = response.bytes_stream().eventsource();
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(event) => {
+                    for delta in into_stream_items(process_event(event)) {
+                        yield delta;
+                    }
+                }
+                Err(e) => {
+                    yield Err(reqwest_es::Error::from(e).into());
+                    return;
+                }
+            }
+        }
+        yield Ok(MessageDelta::Done);
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    #[default]
+    OpenAi,
+    Anthropic,
+    Ollama,
+    Azure,
+    Gemini,
+}
+
+#[derive(Error, Debug)]
+pub enum ProviderError {
+    #[error("Something went wrong while making the API request")]
+    RequestError(#[from] CannotCloneRequestError),
+}
+
+/// A fake HTTP server to test the providers against.
+#[cfg(test)]
+pub(crate) mod test_server {
+    use crate::gpt::ApiConfig;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Starts a server that answers a single request with `status` and a chunked body, and
+    /// returns the config to reach it. The chunks are sent with a pause in between, so the client
+    /// reads them one at a time.
+    pub async fn serve(status: u16, chunks: &[&str]) -> ApiConfig {
+        let chunks: Vec<String> = chunks.iter().map(|chunk| chunk.to_string()).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_request(&mut stream).await;
+
+            let head = format!(
+                "HTTP/1.1 {} Status\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+                status
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            for chunk in chunks {
+                let chunk = format!("{:x}\r\n{}\r\n", chunk.len(), chunk);
+                stream.write_all(chunk.as_bytes()).await.unwrap();
+                stream.flush().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            stream.write_all(b"0\r\n\r\n").await.unwrap();
+        });
+
+        ApiConfig {
+            api_key: None,
+            base_url: format!("http://{}", address),
+            headers: HashMap::new(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Reads the headers and the body of a request, so the client doesn't see the connection
+    /// close before it has sent everything.
+    async fn read_request(stream: &mut TcpStream) {
+        let mut request: Vec<u8> = vec![];
+        let mut buffer = [0; 4096];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+
+            let text = String::from_utf8_lossy(&request);
+            if let Some(head_end) = text.find("\r\n\r\n") {
+                let content_length: usize = text[..head_end]
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse().ok())
+                    .unwrap_or(0);
+                if request.len() >= head_end + 4 + content_length {
+                    return;
+                }
+            }
+            if read == 0 {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use super::{build_request, into_stream_items, send, Auth};
+use super::{ChatProvider, DeltaStream, ProviderError};
+use crate::gpt::{
+    ApiConfig, ApiError, ApiMessage, FinishReason, MessageDelta, Request, ResponseFormat, Role,
+    SamplingParameters, StreamError, Usage,
+};
+use serde::Serialize;
+use tokio_stream::StreamExt;
+
+pub const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+
+/// Talks to the native Ollama API, which streams newline delimited JSON instead of SSE.
+pub struct OllamaProvider {
+    config: ApiConfig,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    options: Options,
+    /// `"json"` for JSON mode, or a JSON schema the response should match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+}
+
+/// Ollama takes images as a separate list of base64 strings instead of content parts.
+#[derive(Serialize, Debug)]
+struct OllamaMessage {
+    role: Role,
+    content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+impl From<ApiMessage> for OllamaMessage {
+    fn from(message: ApiMessage) -> Self {
+        Self {
+            role: message.role,
+            content: message.content.get_text(),
+            images: message
+                .content
+                .get_images()
+                .into_iter()
+                .map(|image| image.data.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Ollama takes the sampling parameters in a separate `options` object, and calls `max_tokens`
+/// `num_predict`.
+#[derive(Serialize, Debug)]
+struct Options {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+}
+
+impl From<SamplingParameters> for Options {
+    fn from(parameters: SamplingParameters) -> Self {
+        Self {
+            temperature: parameters.temperature,
+            top_p: parameters.top_p,
+            num_predict: parameters.max_tokens,
+            presence_penalty: parameters.presence_penalty,
+            frequency_penalty: parameters.frequency_penalty,
+            stop: parameters.stop,
+            seed: parameters.seed,
+        }
+    }
+}
+
+impl From<Request> for ChatRequest {
+    fn from(request: Request) -> Self {
+        Self {
+            model: request.model,
+            messages: request.messages.into_iter().map(|m| m.into()).collect(),
+            stream: request.stream,
+            options: request.parameters.into(),
+            format: request.response_format.map(|format| match format {
+                ResponseFormat::JsonObject => serde_json::Value::from("json"),
+                ResponseFormat::JsonSchema { json_schema } => json_schema.schema,
+            }),
+        }
+    }
+}
+
+impl OllamaProvider {
+    pub fn new(config: ApiConfig) -> Self {
+        Self { config }
+    }
+
+    /// Ollama doesn't need an API key, but one can be set when it runs behind a proxy.
+    fn build_request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        build_request(&self.config, &Auth::Bearer, method, path)
+    }
+
+    /// Returns the names of the models that are installed on the Ollama server.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the server cannot be reached or returns something
+    /// that isn't a model list.
+    pub async fn list_models(&self) -> Result<Vec<String>, reqwest::Error> {
+        let tags: ollama_types::Tags = self
+            .build_request(reqwest::Method::GET, "api/tags")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(tags.models.into_iter().map(|model| model.name).collect())
+    }
+
+    /// Classifies an error response, Ollama responds with `{"error": ...}`. A model that isn't
+    /// installed gives a 404.
+    fn parse_error(status: reqwest::StatusCode, body: Option<&str>) -> ApiError {
+        let message = body
+            .and_then(|body| serde_json::from_str::<ollama_types::ErrorBody>(body).ok())
+            .map(|body| body.error);
+
+        match (status.as_u16(), message) {
+            (404, Some(message)) => ApiError::ModelNotFound(message),
+            (status, _) if status >= 500 => ApiError::ServerError(status),
+            (_, Some(message)) => ApiError::Other(message),
+            (_, None) => ApiError::from_response(status, body),
+        }
+    }
+
+    /// The final chunk carries both the token counts and the reason generation stopped.
+    fn process_line(line: &[u8]) -> Result<Vec<MessageDelta>, StreamError> {
+        let data: ollama_types::ChatChunk = serde_json::from_slice(line)?;
+
+        if let Some(error) = data.error {
+            return Err(ApiError::Other(error).into());
+        }
+
+        if data.done {
+            let mut deltas = vec![MessageDelta::Usage(Usage {
+                prompt_tokens: data.prompt_eval_count,
+                cached_tokens: None,
+                completion_tokens: data.eval_count,
+                reasoning_tokens: None,
+            })];
+            if let Some(done_reason) = data.done_reason {
+                deltas.push(MessageDelta::FinishReason(
+                    0,
+                    FinishReason::from_provider(&done_reason),
+                ));
+            }
+            return Ok(deltas);
+        }
+
+        match data.message {
+            Some(message) => Ok(vec![MessageDelta::Delta(0, message.content)]),
+            None => Ok(vec![MessageDelta::NoData]),
+        }
+    }
+}
+
+impl ChatProvider for OllamaProvider {
+    fn stream_chat(&self, request: Request) -> Result<DeltaStream, ProviderError> {
+        let request_builder = self
+            .build_request(reqwest::Method::POST, "api/chat")
+            .json(&ChatRequest::from(request));
+
+        Ok(Box::pin(async_stream::stream! {
+            let response = match send(request_builder, Self::parse_error).await {
+                Ok(response) => response,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+
+            let mut chunks = response.bytes_stream();
+            let mut buffer: Vec<u8> = vec![];
+            while let Some(chunk) = chunks.next().await {
+                match chunk {
+                    Ok(chunk) => buffer.extend_from_slice(&chunk),
+                    Err(e) => {
+                        yield Err(e.into());
+                        return;
+                    }
+                }
+
+                // A chunk can contain any number of lines, or only part of one
+                while let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=newline).collect();
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    for delta in into_stream_items(Self::process_line(&line)) {
+                        yield delta;
+                    }
+                }
+            }
+
+            if !buffer.iter().all(u8::is_ascii_whitespace) {
+                for delta in into_stream_items(Self::process_line(&buffer)) {
+                    yield delta;
+                }
+            }
+            yield Ok(MessageDelta::Done);
+        }))
+    }
+}
+
+mod ollama_types {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    pub struct ChatChunk {
+        pub message: Option<ChunkMessage>,
+        #[serde(default)]
+        pub done: bool,
+        /// `stop` or `length`, only present when `done` is true
+        pub done_reason: Option<String>,
+        pub prompt_eval_count: Option<usize>,
+        pub eval_count: Option<usize>,
+        pub error: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ChunkMessage {
+        pub content: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ErrorBody {
+        pub error: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Tags {
+        pub models: Vec<Tag>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Tag {
+        pub name: String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::Message;
+    use crate::providers::test_server;
+
+    const FIRST: &str = r#"{"message":{"role":"assistant","content":"Hello"},"done":false}"#;
+    const SECOND: &str = r#"{"message":{"role":"assistant","content":" world"},"done":false}"#;
+    const DONE: &str =
+        r#"{"done":true,"done_reason":"stop","prompt_eval_count":12,"eval_count":2}"#;
+
+    /// What the provider streamed for a single request.
+    #[derive(Default)]
+    struct Streamed {
+        content: String,
+        usage: Usage,
+        finish_reason: Option<FinishReason>,
+        errors: Vec<StreamError>,
+        done: bool,
+    }
+
+    async fn stream(chunks: &[&str]) -> Streamed {
+        let provider = OllamaProvider::new(test_server::serve(200, chunks).await);
+        let request = Request::new(vec![Message::new(Role::user, "Hi".to_string())], "llama3");
+        let mut deltas = provider.stream_chat(request).unwrap();
+
+        let mut streamed = Streamed::default();
+        while let Some(delta) = deltas.next().await {
+            match delta {
+                Ok(MessageDelta::Delta(0, content)) => streamed.content.push_str(&content),
+                Ok(MessageDelta::Usage(usage)) => streamed.usage.update(usage),
+                Ok(MessageDelta::FinishReason(0, finish_reason)) => {
+                    streamed.finish_reason = Some(finish_reason)
+                }
+                Ok(MessageDelta::Done) => streamed.done = true,
+                Ok(_) => {}
+                Err(e) => streamed.errors.push(e),
+            }
+        }
+        streamed
+    }
+
+    fn assert_complete(streamed: &Streamed) {
+        assert!(streamed.errors.is_empty(), "{:?}", streamed.errors);
+        assert_eq!(streamed.content, "Hello world");
+        assert_eq!(streamed.usage.prompt_tokens, Some(12));
+        assert_eq!(streamed.usage.completion_tokens, Some(2));
+        assert_eq!(streamed.finish_reason, Some(FinishReason::Stop));
+        assert!(streamed.done);
+    }
+
+    #[tokio::test]
+    async fn joins_lines_split_across_chunks() {
+        let (first_start, first_end) = FIRST.split_at(20);
+        let (done_start, done_end) = DONE.split_at(10);
+        let chunks = [
+            first_start,
+            first_end,
+            "\n",
+            SECOND,
+            "\n",
+            done_start,
+            done_end,
+            "\n",
+        ];
+        assert_complete(&stream(&chunks).await);
+    }
+
+    #[tokio::test]
+    async fn splits_several_lines_in_one_chunk() {
+        let chunk = format!("{FIRST}\n{SECOND}\n{DONE}\n");
+        assert_complete(&stream(&[&chunk]).await);
+    }
+
+    #[tokio::test]
+    async fn reads_a_trailing_line_without_newline() {
+        let chunk = format!("{FIRST}\n{SECOND}\n");
+        assert_complete(&stream(&[&chunk, DONE]).await);
+    }
+
+    #[tokio::test]
+    async fn returns_an_error_line_as_error() {
+        let streamed = stream(&[
+            r#"{"error":"model 'llama9' not found, try pulling it first"}"#,
+            "\n",
+        ])
+        .await;
+
+        assert_eq!(streamed.content, "");
+        match streamed.errors.as_slice() {
+            [StreamError::ApiError(ApiError::Other(message))] => {
+                assert!(message.contains("not found"))
+            }
+            errors => panic!("expected a single API error, got {:?}", errors),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_a_missing_model() {
+        let config = test_server::serve(
+            404,
+            &[r#"{"error":"model \"llama9\" not found, try pulling it first"}"#],
+        )
+        .await;
+        let provider = OllamaProvider::new(config);
+        let request = Request::new(vec![Message::new(Role::user, "Hi".to_string())], "llama9");
+        let mut deltas = provider.stream_chat(request).unwrap();
+
+        match deltas.next().await {
+            Some(Err(StreamError::ErrorResponse {
+                error: ApiError::ModelNotFound(message),
+                ..
+            })) => assert!(message.contains("llama9")),
+            delta => panic!("expected a missing model, got {:?}", delta),
+        }
+        assert!(deltas.next().await.is_none());
+    }
+}
@@ -0,0 +1,295 @@
+use super::{build_request, stream_events, Auth};
+use super::{ChatProvider, DeltaStream, ProviderError};
+use crate::gpt::{
+    ApiConfig, ApiError, FinishReason, MessageDelta, Request, StreamError, ToolCallDelta, Usage,
+};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// Talks to the OpenAI chat completions API, or anything that speaks the same SSE protocol.
+pub struct OpenAiProvider {
+    config: ApiConfig,
+    /// Path of the chat completions endpoint relative to the base url
+    path: String,
+    /// `Auth::Bearer`, or `Auth::Header("api-key")` for Azure OpenAI
+    auth: Auth,
+    /// Whether the server understands `stream_options`, the API version that Azure uses by
+    /// default rejects it.
+    stream_usage: bool,
+}
+
+impl OpenAiProvider {
+    pub fn new(config: ApiConfig) -> Self {
+        Self {
+            config,
+            path: "chat/completions".to_string(),
+            auth: Auth::Bearer,
+            stream_usage: true,
+        }
+    }
+
+    /// Creates a provider that talks to an Azure OpenAI deployment. `config.base_url` should point
+    /// at the resource, e.g. `https://my-resource.openai.azure.com`.
+    pub fn azure(config: ApiConfig, deployment: &str, api_version: &str) -> Self {
+        Self {
+            config,
+            path: format!(
+                "openai/deployments/{}/chat/completions?api-version={}",
+                deployment, api_version
+            ),
+            auth: Auth::Header("api-key"),
+            stream_usage: false,
+        }
+    }
+
+    /// Returns the ids of the models the server offers, from `GET /models`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the server cannot be reached or returns something
+    /// that isn't a model list.
+    pub async fn list_models(&self) -> Result<Vec<String>, reqwest::Error> {
+        let models: openai_types::ModelList =
+            build_request(&self.config, &self.auth, reqwest::Method::GET, "models")
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+        Ok(models.data.into_iter().map(|model| model.id).collect())
+    }
+
+    /// Besides the delta itself, every chunk also carries the system fingerprint. The usage is
+    /// sent in a final chunk without choices.
+    fn process_event(event: eventsource_stream::Event) -> Result<Vec<MessageDelta>, StreamError> {
+        let data = event.data;
+
+        if data == "[DONE]" {
+            return Ok(vec![MessageDelta::Done]);
+        }
+
+        // Parse data
+        let data: openai_types::EventData = serde_json::from_str(&data)?;
+
+        // Azure sends the results of its prompt filter in a first chunk without choices
+        if data.choices.is_empty() && data.usage.is_none() {
+            return Ok(vec![MessageDelta::NoData]);
+        }
+
+        let mut deltas: Vec<MessageDelta> = vec![];
+        if let Some(system_fingerprint) = data.system_fingerprint {
+            deltas.push(MessageDelta::SystemFingerprint(system_fingerprint));
+        }
+        if let Some(usage) = data.usage {
+            deltas.push(MessageDelta::Usage(Usage {
+                prompt_tokens: Some(usage.prompt_tokens),
+                cached_tokens: usage
+                    .prompt_tokens_details
+                    .and_then(|details| details.cached_tokens),
+                completion_tokens: Some(usage.completion_tokens),
+                reasoning_tokens: usage
+                    .completion_tokens_details
+                    .and_then(|details| details.reasoning_tokens),
+            }));
+        }
+
+        // A single chunk can contain a role, content and tool call fragments at the same time,
+        // for any number of choices
+        for choice in &data.choices {
+            let delta = &choice.delta;
+            if let Some(role) = &delta.role {
+                deltas.push(MessageDelta::Role(role.clone()));
+            }
+            if let Some(content) = &delta.content {
+                deltas.push(MessageDelta::Delta(choice.index, content.into()));
+            }
+            if let Some(logprobs) = &choice.logprobs {
+                if let Some(content) = &logprobs.content {
+                    deltas.push(MessageDelta::Logprobs(choice.index, content.clone()));
+                }
+            }
+
+            // Tool calls are only supported for the first choice
+            if choice.index != 0 {
+                continue;
+            }
+            for tool_call in delta.tool_calls.iter().flatten() {
+                deltas.push(MessageDelta::ToolCall(ToolCallDelta {
+                    index: tool_call.index,
+                    id: tool_call.id.clone(),
+                    name: tool_call.function.as_ref().and_then(|f| f.name.clone()),
+                    arguments: tool_call
+                        .function
+                        .as_ref()
+                        .and_then(|f| f.arguments.clone()),
+                }));
+            }
+        }
+        for choice in &data.choices {
+            if let Some(finish_reason) = &choice.finish_reason {
+                deltas.push(MessageDelta::FinishReason(
+                    choice.index,
+                    FinishReason::from_provider(finish_reason),
+                ));
+            }
+        }
+        if deltas.is_empty() {
+            deltas.push(MessageDelta::NoData);
+        }
+
+        Ok(deltas)
+    }
+}
+
+impl ChatProvider for OpenAiProvider {
+    fn stream_chat(&self, mut request: Request) -> Result<DeltaStream, ProviderError> {
+        if !self.stream_usage {
+            request.stream_options = None;
+        }
+
+        let request_builder =
+            build_request(&self.config, &self.auth, reqwest::Method::POST, &self.path)
+                .json(&request);
+
+        Ok(stream_events(
+            request_builder,
+            Self::process_event,
+            ApiError::from_response,
+        ))
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    fn supports_choice_count(&self) -> bool {
+        true
+    }
+
+    fn supports_logprobs(&self) -> bool {
+        true
+    }
+}
+
+mod openai_types {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    pub struct EventData {
+        pub object: String,
+        pub created: isize,
+        pub model: String,
+        pub system_fingerprint: Option<String>,
+        pub choices: Vec<Choice>,
+        /// Only set in the final chunk, when `stream_options.include_usage` was requested
+        pub usage: Option<Usage>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ModelList {
+        pub data: Vec<ModelEntry>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ModelEntry {
+        pub id: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Usage {
+        pub prompt_tokens: usize,
+        pub completion_tokens: usize,
+        pub prompt_tokens_details: Option<PromptTokensDetails>,
+        pub completion_tokens_details: Option<CompletionTokensDetails>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct PromptTokensDetails {
+        pub cached_tokens: Option<usize>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct CompletionTokensDetails {
+        pub reasoning_tokens: Option<usize>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Choice {
+        pub finish_reason: Option<String>,
+        pub index: usize,
+        pub delta: Delta,
+        pub logprobs: Option<Logprobs>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Logprobs {
+        pub content: Option<Vec<crate::gpt::TokenLogprob>>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Delta {
+        pub role: Option<crate::gpt::Role>,
+        pub content: Option<String>,
+        pub tool_calls: Option<Vec<ToolCallChunk>>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ToolCallChunk {
+        pub index: usize,
+        pub id: Option<String>,
+        pub function: Option<FunctionChunk>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct FunctionChunk {
+        pub name: Option<String>,
+        pub arguments: Option<String>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(data: &str) -> Vec<MessageDelta> {
+        OpenAiProvider::process_event(eventsource_stream::Event {
+            data: data.to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn ignores_azure_prompt_filter_chunk() {
+        // The first chunk of an Azure OpenAI stream
+        let deltas = process(
+            r#"{"choices":[],"created":0,"id":"","model":"","object":"","prompt_filter_results":[{"prompt_index":0,"content_filter_results":{"hate":{"filtered":false,"severity":"safe"},"self_harm":{"filtered":false,"severity":"safe"},"sexual":{"filtered":false,"severity":"safe"},"violence":{"filtered":false,"severity":"safe"}}}]}"#,
+        );
+
+        assert!(matches!(deltas.as_slice(), [MessageDelta::NoData]));
+    }
+
+    #[test]
+    fn reads_content_and_usage() {
+        let deltas = process(
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1718000000,"model":"gpt-4o","system_fingerprint":"fp_1","choices":[{"index":0,"delta":{"content":"Hi"},"logprobs":null,"finish_reason":null}]}"#,
+        );
+        assert!(matches!(
+            deltas.as_slice(),
+            [MessageDelta::SystemFingerprint(_), MessageDelta::Delta(0, content)] if content == "Hi"
+        ));
+
+        let deltas = process(
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1718000000,"model":"gpt-4o","choices":[],"usage":{"prompt_tokens":9,"completion_tokens":1,"total_tokens":10}}"#,
+        );
+        assert!(matches!(
+            deltas.as_slice(),
+            [MessageDelta::Usage(Usage {
+                prompt_tokens: Some(9),
+                completion_tokens: Some(1),
+                ..
+            })]
+        ));
+    }
+}
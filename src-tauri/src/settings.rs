@@ -1,56 +1,251 @@
+use crate::context::ContextStrategy;
+use crate::gpt::{ApiConfig, ReasoningEffort, RetryPolicy, SamplingParameters};
+use crate::models::{Model, ModelRegistry};
+use crate::providers::{
+    anthropic::{self, AnthropicProvider},
+    gemini::{self, GeminiProvider},
+    ollama::{self, OllamaProvider},
+    openai::{self, OpenAiProvider},
+    ChatProvider, ProviderKind,
+};
 use directories::BaseDirs;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tauri::async_runtime::Mutex;
+use thiserror::Error;
 use toml;
 
+#[derive(Error, Debug)]
+pub enum SettingsError {
+    #[error("Please provide an API key in the settings menu")]
+    MissingApiKey,
+
+    #[error("Please configure an Azure resource and deployment in the settings menu")]
+    MissingAzureDeployment,
+
+    #[error("The proxy url is invalid: {0}")]
+    InvalidProxy(reqwest::Error),
+
+    #[error("Could not read the certificate {}: {1}", .0.display())]
+    InvalidCertificate(PathBuf, String),
+
+    #[error("Could not create the HTTP client: {0}")]
+    HttpClient(reqwest::Error),
+
+    #[error("Unknown model {0}, pick one from the list or add it to the model catalog")]
+    UnknownModel(String),
+
+    #[error("The models of this provider can't be listed")]
+    ModelListUnsupported,
+
+    #[error("Could not list the provider's models: {0}")]
+    ModelList(reqwest::Error),
+
+    #[error("The provider took too long to list its models")]
+    ModelListTimedOut,
+}
+
+pub const DEFAULT_AZURE_API_VERSION: &str = "2024-02-01";
+
+/// Identifies a model deployment on an Azure OpenAI resource. Azure doesn't tell us which model
+/// a deployment runs, so costs are calculated using `Settings.model`.
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
-pub enum Model {
-    Gpt4o,
-    Gpt4Turbo,
-    Gpt432K,
-    Gpt4,
-    Gpt3,
+pub struct AzureSettings {
+    /// Name of the Azure OpenAI resource, used to build `https://{resource}.openai.azure.com`.
+    resource: String,
+    deployment: String,
+    #[serde(default = "default_azure_api_version")]
+    api_version: String,
 }
 
-impl Model {
-    pub fn calculate_cost(&self, prompt_tokens: usize, completion_tokens: usize) -> f32 {
-        match self {
-            Self::Gpt3 => (prompt_tokens + completion_tokens) as f32 * 0.000002,
-            Self::Gpt4 => prompt_tokens as f32 * 0.00003 + completion_tokens as f32 * 0.00006,
-            Self::Gpt432K => prompt_tokens as f32 * 0.00006 + completion_tokens as f32 * 0.00012,
-            Self::Gpt4Turbo => prompt_tokens as f32 * 0.00001 + completion_tokens as f32 * 0.00003,
-            Self::Gpt4o => prompt_tokens as f32 * 0.000005 + completion_tokens as f32 * 0.000015
+fn default_azure_api_version() -> String {
+    DEFAULT_AZURE_API_VERSION.to_string()
+}
+
+/// How API requests are sent. The shared HTTP client is rebuilt whenever these change.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct NetworkSettings {
+    /// `http://`, `https://` or `socks5://` url of a proxy that every request goes through.
+    proxy: Option<String>,
+    /// PEM files with certificates that are trusted on top of the system's, for proxies that
+    /// intercept TLS.
+    extra_root_certificates: Vec<PathBuf>,
+    connect_timeout_secs: u64,
+    /// How long to wait for the next chunk of a response before giving up on it.
+    read_timeout_secs: u64,
+    user_agent: Option<String>,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            extra_root_certificates: vec![],
+            connect_timeout_secs: 10,
+            read_timeout_secs: 5,
+            user_agent: None,
         }
     }
 }
 
-impl Model {
-    pub fn to_string(&self) -> &str {
-        match self {
-            Self::Gpt4Turbo => "gpt-4-turbo-preview",
-            Self::Gpt432K => "gpt-4-32k",
-            Self::Gpt4 => "gpt-4",
-            Self::Gpt3 => "gpt-3.5-turbo",
-            Self::Gpt4o => "gpt-4o"
+impl NetworkSettings {
+    pub fn get_read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout_secs)
+    }
+
+    /// Builds the HTTP client that every API request is made with.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the proxy url is invalid or a certificate cannot be
+    /// read.
+    pub fn build_client(&self) -> Result<reqwest::Client, SettingsError> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(self.connect_timeout_secs))
+            .user_agent(match &self.user_agent {
+                Some(user_agent) => user_agent.clone(),
+                None => format!("chatgptauri/{}", env!("CARGO_PKG_VERSION")),
+            });
+
+        if let Some(proxy) = &self.proxy {
+            let proxy = reqwest::Proxy::all(proxy).map_err(SettingsError::InvalidProxy)?;
+            builder = builder.proxy(proxy);
         }
+
+        for path in &self.extra_root_certificates {
+            let pem = fs::read(path)
+                .map_err(|e| SettingsError::InvalidCertificate(path.clone(), e.to_string()))?;
+            let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| SettingsError::InvalidCertificate(path.clone(), e.to_string()))?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        builder.build().map_err(SettingsError::HttpClient)
+    }
+}
+
+/// The HTTP client that every API request is made with, so connections are reused between
+/// requests.
+pub struct HttpClient(Mutex<reqwest::Client>);
+
+impl HttpClient {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self(Mutex::new(client))
+    }
+
+    /// Returns a handle to the client, handles are cheap and share the connection pool.
+    pub async fn get(&self) -> reqwest::Client {
+        self.0.lock().await.clone()
+    }
+}
+
+/// Settings files from before the model registry store the model as a variant of the old `Model`
+/// enum, which is an alias in the registry now, or as a `custom` or `ollama` table.
+fn deserialize_model_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredModel {
+        Id(String),
+        Custom { custom: String },
+        Ollama { ollama: String },
     }
+
+    Ok(match StoredModel::deserialize(deserializer)? {
+        StoredModel::Id(id) => id,
+        StoredModel::Custom { custom } => custom,
+        StoredModel::Ollama { ollama } => ollama,
+    })
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Settings {
     openai_key: Option<String>,
-    model: Model,
+    #[serde(default)]
+    anthropic_key: Option<String>,
+    #[serde(default)]
+    azure_key: Option<String>,
+    #[serde(default)]
+    gemini_key: Option<String>,
+    /// Id of the model, checked against the `ModelRegistry` when the settings are updated. Any id
+    /// is accepted for Ollama and custom endpoints.
+    #[serde(deserialize_with = "deserialize_model_id")]
+    model: String,
+    /// Which vendor's API the requests are sent to.
+    #[serde(default)]
+    provider: ProviderKind,
+    /// Base URL of each provider's API, the provider's official endpoint is used for providers
+    /// that are not in here.
+    #[serde(default)]
+    api_base_urls: HashMap<ProviderKind, String>,
+    /// The single base URL that older versions of the app used for every provider, it is moved
+    /// into `api_base_urls` when the settings are loaded.
+    #[serde(default, skip_serializing)]
+    api_base_url: Option<String>,
+    /// Extra headers that are sent along with every API request.
+    #[serde(default)]
+    extra_headers: HashMap<String, String>,
+    #[serde(default)]
+    azure: Option<AzureSettings>,
+    /// Sampling parameters used by every conversation, unless the conversation overrides them.
+    #[serde(default)]
+    sampling: SamplingParameters,
+    /// Whether the model is allowed to call the built-in tools.
+    #[serde(default)]
+    enable_tools: bool,
+    /// How many times an answer that was cut off by the token limit is continued automatically,
+    /// 0 disables auto-continue.
+    #[serde(default)]
+    auto_continue_limit: usize,
+    /// How requests that hit a rate limit or a transient error are retried.
+    #[serde(default)]
+    retry: RetryPolicy,
+    #[serde(default)]
+    network: NetworkSettings,
+    /// When set, the log probability of every token is requested, together with this many of the
+    /// most likely alternatives.
+    #[serde(default)]
+    top_logprobs: Option<u8>,
+    /// Only used by reasoning models.
+    #[serde(default)]
+    reasoning_effort: ReasoningEffort,
+    /// What happens when a conversation doesn't fit in the context window of the model.
+    #[serde(default)]
+    context_strategy: ContextStrategy,
+    /// The model conversation names are generated with, looked up by `normalize_model`.
+    #[serde(skip)]
+    naming_model: Option<Model>,
 }
 
 impl Settings {
     pub fn _default() -> Self {
         Self {
             openai_key: None,
-            model: Model::Gpt3,
+            anthropic_key: None,
+            azure_key: None,
+            gemini_key: None,
+            model: "gpt-3.5-turbo".to_string(),
+            provider: ProviderKind::OpenAi,
+            api_base_urls: HashMap::new(),
+            api_base_url: None,
+            extra_headers: HashMap::new(),
+            azure: None,
+            sampling: SamplingParameters::default(),
+            enable_tools: false,
+            auto_continue_limit: 0,
+            retry: RetryPolicy::default(),
+            network: NetworkSettings::default(),
+            top_logprobs: None,
+            reasoning_effort: ReasoningEffort::default(),
+            context_strategy: ContextStrategy::default(),
+            naming_model: None,
         }
     }
 
@@ -58,8 +253,227 @@ impl Settings {
         return &self.openai_key;
     }
 
-    pub fn get_model(&self) -> &Model {
-        &self.model
+    /// Looks the configured model up in `models`.
+    pub fn get_model(&self, models: &ModelRegistry) -> Model {
+        models.resolve(&self.model, &self.provider)
+    }
+
+    /// Checks that the configured model is in the registry. Ollama and custom endpoints serve
+    /// models we can't know about, so any id is accepted for them.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the model is unknown.
+    pub fn validate_model(&self, models: &ModelRegistry) -> Result<(), SettingsError> {
+        if self.provider == ProviderKind::Ollama || self.get_api_base_url(&self.provider).is_some()
+        {
+            return Ok(());
+        }
+
+        match models.get(&self.model) {
+            Some(_) => Ok(()),
+            None => Err(SettingsError::UnknownModel(self.model.clone())),
+        }
+    }
+
+    /// Replaces a model name that was stored by an older version of the app with the model's id,
+    /// and looks up the model that names conversations.
+    pub fn normalize_model(&mut self, models: &ModelRegistry) {
+        if let Some(model) = models.get(&self.model) {
+            self.model = model.get_id().to_string();
+        }
+        self.naming_model = Some(models.resolve(&self.get_naming_model_id(), &self.provider));
+    }
+
+    /// Returns the base URL that was configured for `provider`, `None` if the provider's official
+    /// endpoint is used.
+    pub fn get_api_base_url(&self, provider: &ProviderKind) -> Option<&String> {
+        self.api_base_urls.get(provider)
+    }
+
+    pub fn get_sampling(&self) -> &SamplingParameters {
+        &self.sampling
+    }
+
+    pub fn get_enable_tools(&self) -> bool {
+        self.enable_tools
+    }
+
+    pub fn get_auto_continue_limit(&self) -> usize {
+        self.auto_continue_limit
+    }
+
+    pub fn get_retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub fn get_network(&self) -> &NetworkSettings {
+        &self.network
+    }
+
+    pub fn get_top_logprobs(&self) -> Option<u8> {
+        self.top_logprobs
+    }
+
+    pub fn get_reasoning_effort(&self) -> ReasoningEffort {
+        self.reasoning_effort
+    }
+
+    pub fn get_context_strategy(&self) -> ContextStrategy {
+        self.context_strategy
+    }
+
+    /// Returns the model that should be used to generate conversation names, see
+    /// `Settings::get_naming_model_id`.
+    pub fn get_naming_model(&self) -> Model {
+        self.naming_model
+            .clone()
+            .unwrap_or_else(|| Model::unknown(&self.get_naming_model_id(), self.provider.clone()))
+    }
+
+    /// Returns the id of the model that should be used to generate conversation names. The
+    /// official APIs get their cheapest model, custom endpoints get the configured model since
+    /// they probably don't serve `gpt-3.5-turbo`.
+    fn get_naming_model_id(&self) -> String {
+        if self.get_api_base_url(&self.provider).is_some() {
+            return self.model.clone();
+        }
+
+        match self.provider {
+            ProviderKind::OpenAi => "gpt-3.5-turbo".to_string(),
+            ProviderKind::Anthropic => "claude-3-haiku-20240307".to_string(),
+            ProviderKind::Ollama => self.model.clone(),
+            // A deployment only serves a single model
+            ProviderKind::Azure => self.model.clone(),
+            ProviderKind::Gemini => "gemini-1.5-flash".to_string(),
+        }
+    }
+
+    /// Returns everything that is needed to talk to the API of `provider`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no API key is set and the provider's default
+    /// endpoint is used. Custom endpoints (like a local llama.cpp server) usually don't need a key.
+    fn get_api_config(
+        &self,
+        provider: &ProviderKind,
+        api_key: &Option<String>,
+        default_base_url: &str,
+        client: &reqwest::Client,
+    ) -> Result<ApiConfig, SettingsError> {
+        let base_url = self.get_api_base_url(provider);
+        if api_key.is_none() && base_url.is_none() {
+            return Err(SettingsError::MissingApiKey);
+        }
+
+        Ok(ApiConfig {
+            api_key: api_key.clone(),
+            base_url: base_url
+                .cloned()
+                .unwrap_or_else(|| default_base_url.to_string()),
+            headers: self.extra_headers.clone(),
+            client: client.clone(),
+        })
+    }
+
+    /// Builds the chat provider selected in these settings, which sends its requests with
+    /// `client`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the provider's API key is missing, or if Azure is
+    /// selected without a deployment.
+    pub fn get_provider(
+        &self,
+        client: &reqwest::Client,
+    ) -> Result<Arc<dyn ChatProvider>, SettingsError> {
+        Ok(match self.provider {
+            ProviderKind::OpenAi => Arc::new(OpenAiProvider::new(self.get_api_config(
+                &ProviderKind::OpenAi,
+                &self.openai_key,
+                openai::DEFAULT_BASE_URL,
+                client,
+            )?)),
+            ProviderKind::Anthropic => Arc::new(AnthropicProvider::new(self.get_api_config(
+                &ProviderKind::Anthropic,
+                &self.anthropic_key,
+                anthropic::DEFAULT_BASE_URL,
+                client,
+            )?)),
+            ProviderKind::Ollama => Arc::new(self.get_ollama_provider(client)),
+            ProviderKind::Gemini => Arc::new(GeminiProvider::new(self.get_api_config(
+                &ProviderKind::Gemini,
+                &self.gemini_key,
+                gemini::DEFAULT_BASE_URL,
+                client,
+            )?)),
+            ProviderKind::Azure => {
+                let azure = self
+                    .azure
+                    .as_ref()
+                    .ok_or(SettingsError::MissingAzureDeployment)?;
+                let resource_url = format!("https://{}.openai.azure.com", azure.resource);
+                Arc::new(OpenAiProvider::azure(
+                    self.get_api_config(
+                        &ProviderKind::Azure,
+                        &self.azure_key,
+                        &resource_url,
+                        client,
+                    )?,
+                    &azure.deployment,
+                    &azure.api_version,
+                ))
+            }
+        })
+    }
+
+    /// Lists the ids of the models `provider` serves, from its models endpoint.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the provider has no models endpoint we understand,
+    /// or if the request fails or takes longer than the read timeout.
+    pub async fn list_provider_models(
+        &self,
+        provider: &ProviderKind,
+        client: &reqwest::Client,
+    ) -> Result<Vec<String>, SettingsError> {
+        let read_timeout = self.network.get_read_timeout();
+        let model_ids = match provider {
+            ProviderKind::OpenAi => {
+                let provider = OpenAiProvider::new(self.get_api_config(
+                    &ProviderKind::OpenAi,
+                    &self.openai_key,
+                    openai::DEFAULT_BASE_URL,
+                    client,
+                )?);
+                tokio::time::timeout(read_timeout, provider.list_models()).await
+            }
+            ProviderKind::Ollama => {
+                let provider = self.get_ollama_provider(client);
+                tokio::time::timeout(read_timeout, provider.list_models()).await
+            }
+            _ => return Err(SettingsError::ModelListUnsupported),
+        };
+
+        model_ids
+            .map_err(|_| SettingsError::ModelListTimedOut)?
+            .map_err(SettingsError::ModelList)
+    }
+
+    /// Builds an Ollama provider from these settings, regardless of the selected provider. Ollama
+    /// does not need an API key.
+    pub fn get_ollama_provider(&self, client: &reqwest::Client) -> OllamaProvider {
+        OllamaProvider::new(ApiConfig {
+            api_key: None,
+            base_url: self
+                .get_api_base_url(&ProviderKind::Ollama)
+                .cloned()
+                .unwrap_or_else(|| ollama::DEFAULT_BASE_URL.to_string()),
+            headers: self.extra_headers.clone(),
+            client: client.clone(),
+        })
     }
 
     pub fn save(&self) -> Result<(), io::Error> {
@@ -72,8 +486,15 @@ impl Settings {
     pub fn load() -> Result<Self, io::Error> {
         let settings_file = Self::get_settings_file();
         let settings_file_contents = fs::read_to_string(settings_file)?;
-        let deserialized: Self =
+        let mut deserialized: Self =
             toml::from_str(&settings_file_contents).expect("Failed to deserialize settings");
+        if let Some(api_base_url) = deserialized.api_base_url.take() {
+            let provider = deserialized.provider.clone();
+            deserialized
+                .api_base_urls
+                .entry(provider)
+                .or_insert(api_base_url);
+        }
         Ok(deserialized)
     }
 
@@ -85,7 +506,7 @@ impl Settings {
         // Check if this file exists
         if let Err(_) = fs::metadata(&config_file) {
             // Create the file
-            fs::write(&config_file, "model = \"gpt3\"")
+            fs::write(&config_file, "model = \"gpt-3.5-turbo\"")
                 .expect("Failed to write to config directory");
         };
         config_file.to_path_buf()
@@ -101,9 +522,26 @@ pub async fn get_settings(settings: tauri::State<'_, Mutex<Settings>>) -> Result
 #[tauri::command]
 pub async fn update_settings(
     settings_old: tauri::State<'_, Mutex<Settings>>,
+    http_client: tauri::State<'_, HttpClient>,
+    models: tauri::State<'_, Mutex<ModelRegistry>>,
     settings_new: Settings,
-) -> Result<(), ()> {
+) -> Result<(), String> {
     println!("Updating settings");
+    let mut settings_new = settings_new;
+    {
+        let models = models.lock().await;
+        if let Err(e) = settings_new.validate_model(&models) {
+            return Err(e.to_string());
+        }
+        settings_new.normalize_model(&models);
+    }
+
+    // Build the client first, so invalid network settings are never saved
+    let client = match settings_new.network.build_client() {
+        Ok(client) => client,
+        Err(e) => return Err(e.to_string()),
+    };
+    *http_client.0.lock().await = client;
     *settings_old.lock().await = settings_new;
     settings_old
         .lock()
@@ -0,0 +1,97 @@
+use crate::gpt::Message;
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton};
+
+/// Every message is wrapped in `<|start|>{role}<|message|>{content}<|end|>`, which costs this
+/// many tokens on top of the role and content.
+/// https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
+const TOKENS_PER_MESSAGE: usize = 3;
+
+/// Every reply is primed with `<|start|>assistant<|message|>`.
+const TOKENS_PER_REPLY: usize = 3;
+
+/// The BPE encoding a model uses. Models of other vendors use their own tokenizers, which
+/// aren't public, for those `Cl100k` gives a reasonable estimate.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Cl100k,
+    O200k,
+}
+
+/// Counts the tokens in `text`. The vocabularies are bundled with the app, so this works
+/// without network access.
+pub fn count_tokens(text: &str, encoding: Encoding) -> usize {
+    let bpe = match encoding {
+        Encoding::Cl100k => cl100k_base_singleton(),
+        Encoding::O200k => o200k_base_singleton(),
+    };
+    let bpe = bpe.lock();
+    bpe.encode_with_special_tokens(text).len()
+}
+
+/// Counts the tokens a single message takes up in the chat format, including its images and
+/// tool calls.
+pub fn count_message_tokens(message: &Message, encoding: Encoding) -> usize {
+    let mut token_count = TOKENS_PER_MESSAGE
+        + count_tokens(message.get_role().as_str(), encoding)
+        + count_tokens(message.get_content(), encoding);
+
+    for tool_call in message.get_tool_calls().into_iter().flatten() {
+        token_count += count_tokens(&tool_call.function.name, encoding)
+            + count_tokens(&tool_call.function.arguments, encoding);
+    }
+
+    token_count
+        + message
+            .get_attachments()
+            .iter()
+            .map(|attachment| attachment.get_token_count())
+            .sum::<usize>()
+}
+
+/// Counts the tokens of a prompt that consists of `messages`, including the tokens that prime
+/// the reply.
+pub fn count_prompt_tokens<'a>(
+    messages: impl IntoIterator<Item = &'a Message>,
+    encoding: Encoding,
+) -> usize {
+    messages
+        .into_iter()
+        .map(|message| count_message_tokens(message, encoding))
+        .sum::<usize>()
+        + TOKENS_PER_REPLY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::Role;
+
+    #[test]
+    fn counts_tokens_of_both_encodings() {
+        assert_eq!(count_tokens("hello world", Encoding::Cl100k), 2);
+        assert_eq!(count_tokens("hello world", Encoding::O200k), 2);
+        // The example from the OpenAI cookbook
+        assert_eq!(count_tokens("tiktoken is great!", Encoding::Cl100k), 6);
+        assert_eq!(count_tokens("tiktoken is great!", Encoding::O200k), 6);
+    }
+
+    #[test]
+    fn adds_the_chat_format_overhead() {
+        let message = Message::new(Role::user, "hello world".into());
+        // "user" and "hello world" take up 1 and 2 tokens
+        assert_eq!(
+            count_message_tokens(&message, Encoding::Cl100k),
+            TOKENS_PER_MESSAGE + 3
+        );
+
+        let messages = [message.clone(), message];
+        assert_eq!(
+            count_prompt_tokens(&messages, Encoding::Cl100k),
+            2 * (TOKENS_PER_MESSAGE + 3) + TOKENS_PER_REPLY
+        );
+        assert_eq!(count_prompt_tokens([], Encoding::Cl100k), TOKENS_PER_REPLY);
+    }
+}
@@ -0,0 +1,250 @@
+use super::{Tool, ToolError};
+use serde_json::json;
+
+/// Evaluates arithmetic expressions, so the model doesn't have to do arithmetic in its head.
+pub struct Calculator;
+
+impl Tool for Calculator {
+    fn name(&self) -> &str {
+        "calculator"
+    }
+
+    fn description(&self) -> &str {
+        "Evaluates an arithmetic expression and returns the result. Supports + - * / ^, \
+        parentheses, the constants pi and e, and the functions sqrt, abs, ln, log, exp, sin, cos \
+        and tan (in radians)."
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": {
+                    "type": "string",
+                    "description": "The expression to evaluate, for example `2 * (3 + sqrt(16))`"
+                }
+            },
+            "required": ["expression"]
+        })
+    }
+
+    fn call(&self, arguments: serde_json::Value) -> Result<String, ToolError> {
+        let expression = arguments["expression"].as_str().ok_or_else(|| {
+            ToolError::InvalidArguments("`expression` must be a string".to_string())
+        })?;
+
+        let mut parser = Parser {
+            chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+            position: 0,
+        };
+        let result = parser.parse_expression()?;
+        if parser.position != parser.chars.len() {
+            return Err(parser.unexpected());
+        }
+
+        Ok(result.to_string())
+    }
+}
+
+/// Recursive descent parser that evaluates while it parses.
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn unexpected(&self) -> ToolError {
+        match self.peek() {
+            Some(c) => {
+                ToolError::Failed(format!("Unexpected `{}` at position {}", c, self.position))
+            }
+            None => ToolError::Failed("Unexpected end of expression".to_string()),
+        }
+    }
+
+    // expression = term (('+' | '-') term)*
+    fn parse_expression(&mut self) -> Result<f64, ToolError> {
+        let mut value = self.parse_term()?;
+        while let Some(c) = self.peek() {
+            match c {
+                '+' => {
+                    self.position += 1;
+                    value += self.parse_term()?;
+                }
+                '-' => {
+                    self.position += 1;
+                    value -= self.parse_term()?;
+                }
+                _ => break,
+            }
+        }
+        Ok(value)
+    }
+
+    // term = unary (('*' | '/') unary)*
+    fn parse_term(&mut self) -> Result<f64, ToolError> {
+        let mut value = self.parse_unary()?;
+        while let Some(c) = self.peek() {
+            match c {
+                '*' => {
+                    self.position += 1;
+                    value *= self.parse_unary()?;
+                }
+                '/' => {
+                    self.position += 1;
+                    value /= self.parse_unary()?;
+                }
+                _ => break,
+            }
+        }
+        Ok(value)
+    }
+
+    // unary = '-' unary | power
+    fn parse_unary(&mut self) -> Result<f64, ToolError> {
+        if self.peek() == Some('-') {
+            self.position += 1;
+            return Ok(-self.parse_unary()?);
+        }
+        self.parse_power()
+    }
+
+    // power = atom ('^' unary)?, right associative
+    fn parse_power(&mut self) -> Result<f64, ToolError> {
+        let base = self.parse_atom()?;
+        if self.peek() == Some('^') {
+            self.position += 1;
+            return Ok(base.powf(self.parse_unary()?));
+        }
+        Ok(base)
+    }
+
+    // atom = number | identifier | identifier '(' expression ')' | '(' expression ')'
+    fn parse_atom(&mut self) -> Result<f64, ToolError> {
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let value = self.parse_expression()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.parse_number(),
+            Some(c) if c.is_ascii_alphabetic() => self.parse_identifier(),
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<f64, ToolError> {
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_digit() || c == '.') {
+                break;
+            }
+            self.position += 1;
+        }
+
+        let number: String = self.chars[start..self.position].iter().collect();
+        number
+            .parse()
+            .map_err(|_| ToolError::Failed(format!("Invalid number `{}`", number)))
+    }
+
+    fn parse_identifier(&mut self) -> Result<f64, ToolError> {
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if !c.is_ascii_alphanumeric() {
+                break;
+            }
+            self.position += 1;
+        }
+        let identifier: String = self.chars[start..self.position].iter().collect();
+
+        match identifier.as_str() {
+            "pi" => return Ok(std::f64::consts::PI),
+            "e" => return Ok(std::f64::consts::E),
+            _ => {}
+        }
+
+        let function: fn(f64) -> f64 = match identifier.as_str() {
+            "sqrt" => f64::sqrt,
+            "abs" => f64::abs,
+            "ln" => f64::ln,
+            "log" => f64::log10,
+            "exp" => f64::exp,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            _ => {
+                return Err(ToolError::Failed(format!(
+                    "Unknown identifier `{}`",
+                    identifier
+                )))
+            }
+        };
+
+        self.expect('(')?;
+        let argument = self.parse_expression()?;
+        self.expect(')')?;
+        Ok(function(argument))
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ToolError> {
+        if self.peek() != Some(expected) {
+            return Err(self.unexpected());
+        }
+        self.position += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(expression: &str) -> Result<String, ToolError> {
+        Calculator.call(json!({ "expression": expression }))
+    }
+
+    #[test]
+    fn follows_operator_precedence() {
+        assert_eq!(evaluate("2 + 3 * 4").unwrap(), "14");
+        assert_eq!(evaluate("(2 + 3) * 4").unwrap(), "20");
+        assert_eq!(evaluate("10 - 4 - 3").unwrap(), "3");
+        assert_eq!(evaluate("2 ^ 3 ^ 2").unwrap(), "512");
+        assert_eq!(evaluate("-2 ^ 2").unwrap(), "-4");
+        assert_eq!(evaluate("7 / 2").unwrap(), "3.5");
+    }
+
+    #[test]
+    fn evaluates_functions_and_constants() {
+        assert_eq!(evaluate("2 * (3 + sqrt(16))").unwrap(), "14");
+        assert_eq!(evaluate("abs(-1.5) + log(100)").unwrap(), "3.5");
+        assert_eq!(evaluate("ln(e)").unwrap(), "1");
+        assert_eq!(evaluate("cos(pi)").unwrap(), "-1");
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(matches!(
+            evaluate("2 +"),
+            Err(ToolError::Failed(message)) if message == "Unexpected end of expression"
+        ));
+        assert!(matches!(
+            evaluate("2)"),
+            Err(ToolError::Failed(message)) if message == "Unexpected `)` at position 1"
+        ));
+        assert!(matches!(
+            evaluate("foo(1)"),
+            Err(ToolError::Failed(message)) if message == "Unknown identifier `foo`"
+        ));
+        assert!(matches!(evaluate("1..2"), Err(ToolError::Failed(_))));
+        assert!(matches!(
+            Calculator.call(json!({ "expression": 1 })),
+            Err(ToolError::InvalidArguments(_))
+        ));
+    }
+}
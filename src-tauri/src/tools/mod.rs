@@ -0,0 +1,98 @@
+use crate::gpt::{FunctionDefinition, ToolCall, ToolDefinition};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub mod calculator;
+
+/// A function that the model can call. Tools run locally, their output is sent back to the model
+/// as a `Role::tool` message.
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn description(&self) -> &str;
+
+    /// JSON schema of the arguments object.
+    fn parameters(&self) -> serde_json::Value;
+
+    /// Runs the tool with the arguments the model provided.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the arguments are invalid or the tool fails.
+    fn call(&self, arguments: serde_json::Value) -> Result<String, ToolError>;
+}
+
+#[derive(Error, Debug)]
+pub enum ToolError {
+    #[error("Unknown tool: {0}")]
+    UnknownTool(String),
+
+    #[error("Arguments are not valid JSON: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+
+    #[error("Invalid arguments: {0}")]
+    InvalidArguments(String),
+
+    #[error("{0}")]
+    Failed(String),
+}
+
+/// The tools that are available to the model, keyed by name.
+#[derive(Clone)]
+pub struct ToolRegistry {
+    tools: HashMap<String, Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self {
+            tools: HashMap::new(),
+        }
+    }
+
+    /// Returns a registry that contains every built-in tool.
+    pub fn with_builtin_tools() -> Self {
+        let mut registry = Self::new();
+        registry.register(calculator::Calculator);
+        registry
+    }
+
+    pub fn register(&mut self, tool: impl Tool + 'static) {
+        self.tools.insert(tool.name().to_string(), Arc::new(tool));
+    }
+
+    /// Returns the definitions of all registered tools, so they can be sent along with a request.
+    pub fn get_definitions(&self) -> Vec<ToolDefinition> {
+        let mut definitions: Vec<ToolDefinition> = self
+            .tools
+            .values()
+            .map(|tool| ToolDefinition {
+                kind: "function".to_string(),
+                function: FunctionDefinition {
+                    name: tool.name().to_string(),
+                    description: tool.description().to_string(),
+                    parameters: tool.parameters(),
+                },
+            })
+            .collect();
+        definitions.sort_by(|a, b| a.function.name.cmp(&b.function.name));
+        definitions
+    }
+
+    /// Runs the tool that `tool_call` refers to. Errors are returned as text, so the model can see
+    /// what went wrong and try again.
+    pub fn call(&self, tool_call: &ToolCall) -> String {
+        let result = match self.tools.get(&tool_call.function.name) {
+            Some(tool) => serde_json::from_str(&tool_call.function.arguments)
+                .map_err(ToolError::from)
+                .and_then(|arguments| tool.call(arguments)),
+            None => Err(ToolError::UnknownTool(tool_call.function.name.clone())),
+        };
+
+        match result {
+            Ok(output) => output,
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+}
@@ -1,5 +1,96 @@
+export interface ToolCall {
+    id: string,
+    type: "function",
+    function: { name: string, arguments: string }
+}
+
+export interface Attachment {
+    file_name: string,
+    mime_type: string,
+    width: number,
+    height: number
+}
+
+// Every event of a conversation carries the id of the conversation it belongs to
+export interface ConversationEvent<T> {
+    conversation_id: number,
+    payload: T
+}
+
+export type FinishReason = "stop" | "length" | "tool_calls" | "content_filter" | { other: string };
+
 export interface ChatMessage {
-    role: "user" | "assistant" | "error"
+    role: "user" | "assistant" | "tool" | "error"
     content: string,
 	cost_dollars?: number
+	cost_estimated?: boolean
+	system_fingerprint?: string
+	tool_calls?: ToolCall[]
+	tool_call_id?: string
+	attachments?: Attachment[]
+	candidates?: string[]
+	selected_candidate?: number
+	finish_reason?: FinishReason | null
+	logprobs?: { token: string, logprob: number }[]
+	reasoning_tokens?: number
+	attempt_costs?: number[]
+	excluded?: boolean
+	pinned?: boolean
+	deleted?: boolean
+}
+
+export type ResponseFormat =
+    | { type: "json_object" }
+    | { type: "json_schema", json_schema: { name: string, schema: object, strict: boolean } };
+
+// Payload of the `cost` event, what the last response cost
+export interface Cost {
+    dollars: number,
+    estimated: boolean,
+    reasoning_tokens: number
+}
+
+export type ApiError =
+    | { kind: "invalid_key" | "quota_exhausted" | "rate_limited" | "context_too_long" | "network_down" }
+    | { kind: "server_error", details: number }
+    | { kind: "model_not_found" | "other", details: string };
+
+export interface ApiErrorEvent {
+    error: ApiError,
+    message: string
+}
+
+export interface ValidationResult {
+    valid: boolean,
+    errors: string[]
+}
+
+export interface TokenProbability {
+    token: string,
+    probability: number,
+    alternatives: { token: string, probability: number }[]
+}
+
+// Where a message stands among its alternatives, from editing a prompt or regenerating an answer
+export interface Branches {
+    selected: number,
+    count: number
+}
+
+// Payload of the `context_trimmed` event
+export interface Trimmed {
+    strategy: "drop_oldest" | "summarize" | "refuse",
+    message_count: number,
+    tokens_before: number,
+    tokens_after: number,
+    // Why the messages couldn't be summarized, they were left out instead
+    summary_error?: string
+}
+
+export interface ConversationStats {
+    message_count: number,
+    token_count: number,
+    context_window: number | null,
+    prompt_cost_dollars: number,
+    total_cost_dollars: number
 }
@@ -1,7 +1,76 @@
-export type Model = "gpt3" | "gpt4" | "gpt432k";
+// The id of a model, as the provider's API knows it
+export type Model = string;
+
+export interface ModelInfo {
+    id: string;
+    name: string | null;
+    provider: Provider;
+    context_window: number | null;
+    vision: boolean;
+    tools: boolean;
+    reasoning: boolean;
+    system_messages: boolean;
+    // Dollars per million tokens, null if unknown
+    input_price: number | null;
+    output_price: number | null;
+    cached_input_factor: number;
+}
+
+export type Provider = "openai" | "anthropic" | "ollama" | "azure" | "gemini";
+
+export interface AzureSettings {
+    resource: string;
+    deployment: string;
+    api_version: string;
+}
+
+export interface SamplingParameters {
+    temperature?: number;
+    top_p?: number;
+    max_tokens?: number;
+    presence_penalty?: number;
+    frequency_penalty?: number;
+    stop?: string[];
+    seed?: number;
+}
+
+export type ReasoningEffort = "low" | "medium" | "high";
+
+// What happens when a conversation doesn't fit in the context window of the model
+export type ContextStrategy = "drop_oldest" | "summarize" | "refuse";
+
+export interface RetryPolicy {
+    max_retries: number;
+    initial_delay_ms: number;
+    max_delay_ms: number;
+}
+
+export interface NetworkSettings {
+    proxy: string | null;
+    extra_root_certificates: string[];
+    connect_timeout_secs: number;
+    read_timeout_secs: number;
+    user_agent: string | null;
+}
 
 export interface Settings {
     openai_key: string | null;
+    anthropic_key: string | null;
+    azure_key: string | null;
+    gemini_key: string | null;
     model: Model;
+    provider: Provider;
+    // Base URL of each provider's API, providers that are missing use their official endpoint
+    api_base_urls: Partial<Record<Provider, string>>;
+    extra_headers: Record<string, string>;
+    azure: AzureSettings | null;
+    sampling: SamplingParameters;
+    enable_tools: boolean;
+    auto_continue_limit: number;
+    retry: RetryPolicy;
+    network: NetworkSettings;
+    top_logprobs: number | null;
+    reasoning_effort: ReasoningEffort;
+    context_strategy: ContextStrategy;
 }
 